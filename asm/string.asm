@@ -1,4 +1,12 @@
-push 1
-push "abc"
-push 2
+push "axo"
+push "lotl"
+concat
+dup
+len             ;; 7
+swap
+push 3
+char_at         ;; 'l'
+push 'o'
+push 'k'
+collect_char_list 2
 HALT
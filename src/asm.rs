@@ -1,140 +1,618 @@
 use crate::vm::bytecode::ByteCode;
 use crate::vm::value::Value;
 use crate::vm::object::ObjType;
+use crate::frontend::token::Span;
+use crate::frontend::{parser, macros};
+use crate::literal::{self, LiteralError};
 
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::fmt::{self, Display, Formatter};
 use std::rc::Rc;
 use regex::Regex;
 
-fn pre_process(file: String) -> (Vec<String>, HashMap<String, usize>) {
-    let re_trim = Regex::new(r"\s*;;.+$").unwrap();
-    let re_empty = Regex::new(r"^\s*$").unwrap();
-    let re_lable = Regex::new(r"\s+<- (.+)$").unwrap();
+#[derive (Debug)]
+pub enum AsmError {
+    // what was written, and the closest thing that exists
+    UnknownInstruction(String, Option<String>, Span),
+    UndefinedLable(String, Option<String>, Span),
+    DuplicateLable(String, Span),
+    InvalidArgument(String, Span),  // the instruction is known, what follows it isn't
+    InvalidLiteral(LiteralError, Span),  // a string or a char
+    UnclosedFn(Span),  // `push fn` without its `end`
+    UnexpectedEnd(Span),
+    UnclosedMacro(Span),  // `%macro` without its `%endmacro`
+    AlreadyDefined(String, Span),  // a %define or a %macro
+    WrongNumberOfArgument(usize, usize, Span),  // of a macro, expected and got
+    TooDeep(String, Span),  // a macro expanding into itself
+    IncludeNotFound(String, Span),
+    CyclicInclude(String, Span),
+    InFile(String, Box<AsmError>, Span),  // the error in an included file, and where it is included
+}
 
-    let mut processed = vec![];
-    let mut lable_pool = HashMap::new();
+impl AsmError {
+    pub fn span(&self) -> Span {
+        match self {
+            AsmError::UnknownInstruction(_, _, span)
+                | AsmError::UndefinedLable(_, _, span)
+                | AsmError::DuplicateLable(_, span)
+                | AsmError::InvalidArgument(_, span)
+                | AsmError::InvalidLiteral(_, span)
+                | AsmError::UnclosedFn(span)
+                | AsmError::UnexpectedEnd(span)
+                | AsmError::UnclosedMacro(span)
+                | AsmError::AlreadyDefined(_, span)
+                | AsmError::WrongNumberOfArgument(_, _, span)
+                | AsmError::TooDeep(_, span)
+                | AsmError::IncludeNotFound(_, span)
+                | AsmError::CyclicInclude(_, span)
+                | AsmError::InFile(_, _, span) => *span,
+        }
+    }
+}
 
-    for raw_line in file.lines() {
-        let line = &re_trim.replace(raw_line, "");  // Cow<'t, str>
-        if re_empty.is_match(line) {
-            continue
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let hint = |hint: &Option<String>| match hint {
+            Some(hint) => format!(", did you mean `{}`?", hint),
+            None => String::new(),
+        };
+        match self {
+            AsmError::UnknownInstruction(line, close, _) =>
+                write!(f, "unknown instruction `{}`{}", line, hint(close)),
+            AsmError::UndefinedLable(lable, close, _) =>
+                write!(f, "undefined label `{}`{}", lable, hint(close)),
+            AsmError::DuplicateLable(lable, _) => write!(f, "label `{}` is already defined", lable),
+            AsmError::InvalidArgument(line, _) => write!(f, "invalid argument in `{}`", line),
+            AsmError::InvalidLiteral(err, _) => write!(f, "{}", err),
+            AsmError::UnclosedFn(_) => write!(f, "function body without `end`"),
+            AsmError::UnexpectedEnd(_) => write!(f, "`end` without `push fn`"),
+            AsmError::UnclosedMacro(_) => write!(f, "macro without `%endmacro`"),
+            AsmError::AlreadyDefined(name, _) => write!(f, "`{}` is already defined", name),
+            AsmError::WrongNumberOfArgument(expected, got, _) =>
+                write!(f, "expected {} arguments, got {}", expected, got),
+            AsmError::TooDeep(name, _) => write!(f, "macro `{}` expands too deep", name),
+            AsmError::IncludeNotFound(path, _) => write!(f, "file `{}` not found", path),
+            AsmError::CyclicInclude(chain, _) => write!(f, "cyclic include: {}", chain),
+            AsmError::InFile(path, err, _) => write!(f, "in {}: {}", path, err),
         }
+    }
+}
+
+// `push fn N` starts a function body of N parameters, `end` closes it.
+// the lines between are assembled as a program of its own
+const NO_ARG: &[(&str, ByteCode)] = &[
+    ("push nil"  , ByteCode::Push(Value::Nil)),
+    ("push true" , ByteCode::Push(Value::Bool(true))),
+    ("push false", ByteCode::Push(Value::Bool(false))),
+    ("HALT", ByteCode::HALT),
+    ("pop" , ByteCode::Pop),
+    ("dup" , ByteCode::Dup),
+    ("swap", ByteCode::Swap),
+    ("ret" , ByteCode::Ret),
+    (">"   , ByteCode::Greater),
+    ("<"   , ByteCode::Less),
+    (">="  , ByteCode::GreaterEq),
+    ("<="  , ByteCode::LessEq),
+    ("=="  , ByteCode::Eq),
+    ("!="  , ByteCode::Neq),
+    ("===" , ByteCode::Seq),
+    ("!==" , ByteCode::Sneq),
+    ("+"   , ByteCode::Add),
+    ("-"   , ByteCode::Sub),
+    ("*"   , ByteCode::Mul),
+    ("/"   , ByteCode::Div),
+    ("%"   , ByteCode::Rem),
+    ("++"  , ByteCode::Inc),
+    ("--"  , ByteCode::Dec),
+    ("&"   , ByteCode::And),
+    ("|"   , ByteCode::Or),
+    ("!"   , ByteCode::Not),
+    ("^"   , ByteCode::Xor),
+    ("print"    , ByteCode::Print),
+    ("println"  , ByteCode::PrintLn),
+    ("read_line", ByteCode::ReadLine),
+    ("exit"     , ByteCode::Exit),
+    ("car"      , ByteCode::Car),
+    ("cdr"      , ByteCode::Cdr),
+    ("cons"     , ByteCode::Cons),
+    ("is_nil"   , ByteCode::IsNil),
+    ("is_list"  , ByteCode::IsList),
+    ("no_match" , ByteCode::NoMatch),
+    ("concat"     , ByteCode::Concat),
+    ("len"        , ByteCode::Len),
+    ("char_at"    , ByteCode::CharAt),
+    ("substr"     , ByteCode::Substr),
+    ("str_to_list", ByteCode::StrToList),
+    ("list_to_str", ByteCode::ListToStr),
+    ("make_cell"  , ByteCode::MakeCell),
+    ("cell_get"   , ByteCode::CellGet),
+    ("cell_set"   , ByteCode::CellSet),
+];
+
+// jmp, pop_jmp_if, pop_jmp_if_not and call also take a lable
+type WithUsize = fn(usize) -> ByteCode;
+
+const WITH_USIZE: &[(&str, WithUsize)] = &[
+    ("jmp"           , ByteCode::Jmp),
+    ("pop_jmp_if"    , ByteCode::PopJmpIf),
+    ("pop_jmp_if_not", ByteCode::PopJmpIfNot),
+    ("call"          , ByteCode::Call),
+    ("get"           , ByteCode::Get),
+    ("set"           , ByteCode::Set),
+    ("arg"           , ByteCode::Arg),
+    ("load_local"    , ByteCode::LoadLocal),
+    ("store_local"   , ByteCode::StoreLocal),
+    ("load_capture"  , ByteCode::LoadCapture),
+    ("load_rec"      , ByteCode::LoadRec),
+    ("make_closure"  , ByteCode::MakeClosure),
+    ("call_native"   , ByteCode::CallNative),
+    ("collect_list"  , ByteCode::CollectList),
+    ("collect_char_list", ByteCode::CollectCharList),
+    ("call_top_fn"      , ByteCode::CallTopFn),
+    ("tail_call_top_fn" , ByteCode::TailCallTopFn),
+];
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(ca != *cb)).min(above + 1).min(row[j] + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+// the candidate a typo could have come from
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+    let limit = (name.chars().count() / 3).max(2);
+    candidates
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(d, _)| *d <= limit && *d < name.chars().count())
+        .min()
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+// the %include a line came from, and where that one came from
+#[derive (Debug)]
+struct Include {
+    path: String,
+    at: Span,  // of the %include, in the file including it
+    from: Option<Rc<Include>>,
+}
+
+// errors in an included file are reported at the %include of the main file
+fn in_file(from: &Option<Rc<Include>>, err: AsmError) -> AsmError {
+    let mut err = err;
+    let mut from = from.clone();
+    while let Some(include) = from {
+        err = AsmError::InFile(include.path.clone(), Box::new(err), include.at);
+        from = include.from.clone();
+    }
+    err
+}
+
+// a line left after comments, blank lines and directives are gone
+struct Line {
+    number: usize,  // in the file, from 1
+    col: usize,  // where the text starts
+    text: String,  // without the lables and the indentation
+    lables: Vec<(String, Span)>,
+    call: Option<Span>,  // of the macro it is expanded from
+    from: Option<Rc<Include>>,
+}
+
+impl Line {
+    fn span(&self) -> Span {
+        self.call.unwrap_or(Span { line: self.number, col: self.col, len: self.text.chars().count() })
+    }
+
+    // where the argument is, when there is one
+    fn arg_span(&self) -> Span {
+        let span = self.span();
+        match self.text.find(' ') {
+            Some(space) if self.call.is_none() => Span { col: span.col + space + 1, len: span.len - space - 1, ..span },
+            _ => span,
+        }
+    }
+}
+
+const MAX_EXPANSION: usize = 256;
+const DIRECTIVES: &[&str] = &["%define", "%macro", "%endmacro", "%include"];
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,  // without comments
+}
+
+// %define NAME value      NAME is value from here on
+// %macro name a b         `name 1 2` is the lines before %endmacro, with a and b replaced.
+// ...                     %%lable is a lable of its own in every expansion
+// %endmacro
+// %include "file.asm"     relative to the file including it
+struct PreProcessor {
+    re_trim: Regex,
+    re_empty: Regex,
+    re_lable: Regex,
+    re_word: Regex,
+    re_define: Regex,
+    re_macro: Regex,
+    re_include: Regex,
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Rc<Macro>>,
+    including: Vec<PathBuf>,
+    depth: usize,  // of the macros being expanded
+    expansions: usize,  // so far, every one has its own %%lables
+    errors: Vec<AsmError>,
+}
+
+impl PreProcessor {
+    fn new() -> Self {
+        PreProcessor {
+            re_trim: Regex::new(r"\s*;;.+$").unwrap(),
+            re_empty: Regex::new(r"^\s*$").unwrap(),
+            re_lable: Regex::new(r"\s+<- (.+)$").unwrap(),
+            re_word: Regex::new(r"[\w%@.]+").unwrap(),
+            re_define: Regex::new(r"^%define (\w+) (.+)$").unwrap(),
+            re_macro: Regex::new(r"^%macro (\w+)((?: \w+)*)$").unwrap(),
+            re_include: Regex::new(r#"^%include "(.+)"$"#).unwrap(),
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            including: vec![],
+            depth: 0,
+            expansions: 0,
+            errors: vec![],
+        }
+    }
+
+    // whole words before any string or char, which are left as they are
+    fn substitute(&self, text: &str, word: impl Fn(&str) -> Option<String>) -> String {
+        let (code, literal) = text.split_at(text.find(['"', '\'']).unwrap_or(text.len()));
+        let code = self.re_word.replace_all(code, |cap: &regex::Captures| word(&cap[0]).unwrap_or_else(|| cap[0].to_string()));
+        format!("{}{}", code, literal)
+    }
+
+    fn pre_process(&mut self, file: &str, dir: &Path, from: Option<Rc<Include>>) -> Vec<Line> {
+        let mut processed = vec![];
+        let raw_lines: Vec<&str> = file.lines().collect();
+        let mut i = 0;
+        while i < raw_lines.len() {
+            let number = i + 1;
+            let line = self.re_trim.replace(raw_lines[i], "").to_string();
+            i += 1;
+            if self.re_empty.is_match(&line) {
+                continue
+            }
+            let indent = line.len() - line.trim_start().len();
+            let span = Span { line: number, col: indent + 1, len: line.trim().chars().count() };
+
+            if let Some(cap) = self.re_macro.captures(line.trim()) {
+                let mut body = vec![];
+                while i < raw_lines.len() && raw_lines[i].trim() != "%endmacro" {
+                    body.push(self.re_trim.replace(raw_lines[i], "").to_string());
+                    i += 1;
+                }
+                if i == raw_lines.len() {
+                    self.errors.push(in_file(&from, AsmError::UnclosedMacro(span)));
+                    break
+                }
+                i += 1;  // %endmacro
+                let name = cap[1].to_string();
+                if self.macros.contains_key(&name) || self.defines.contains_key(&name) {
+                    self.errors.push(in_file(&from, AsmError::AlreadyDefined(name, span)));
+                    continue
+                }
+                let params = cap[2].split_whitespace().map(String::from).collect();
+                self.macros.insert(name, Rc::new(Macro { params, body }));
+            } else {
+                self.line(&line, number, None, dir, &from, &mut processed);
+            }
+        }
+        processed
+    }
+
+    fn line(&mut self, line: &str, number: usize, call: Option<Span>, dir: &Path,
+            from: &Option<Rc<Include>>, processed: &mut Vec<Line>) {
+        let (line, lables) = match self.re_lable.captures(line) {
+            Some(cap) => {
+                let lable = cap.get(1).unwrap();
+                let span = call.unwrap_or(Span { line: number, col: lable.start() + 1, len: lable.as_str().chars().count() });
+                (self.re_lable.replace(line, "").to_string(), vec![(lable.as_str().to_owned(), span)])
+            }
+            None => (line.to_string(), vec![]),
+        };
+        let indent = line.len() - line.trim_start().len();
+        let text = line.trim();
+        let span = call.unwrap_or(Span { line: number, col: indent + 1, len: text.chars().count() });
+        let name = text.split_whitespace().next().unwrap_or(text);
 
-        if re_lable.is_match(line) {
-            let cap = re_lable.captures(line).unwrap();
-            lable_pool.insert(cap[1].to_owned(), processed.len());
-            let line_trimed_lable = re_lable.replace(line, "");
-            processed.push(line_trimed_lable.to_string());
+        if let Some(cap) = self.re_define.captures(text) {
+            let value = self.substitute(cap[2].trim(), |word| self.defines.get(word).cloned());
+            if self.defines.contains_key(&cap[1]) || self.macros.contains_key(&cap[1]) {
+                self.errors.push(in_file(from, AsmError::AlreadyDefined(cap[1].to_string(), span)));
+            } else {
+                self.defines.insert(cap[1].to_string(), value);
+            }
+        } else if let Some(cap) = self.re_include.captures(text) {
+            let path = dir.join(&cap[1]);
+            let Ok(canonical) = path.canonicalize() else {
+                self.errors.push(in_file(from, AsmError::IncludeNotFound(cap[1].to_string(), span)));
+                return
+            };
+            if self.including.contains(&canonical) {
+                let chain = self.including.iter()
+                    .skip_while(|p| **p != canonical)
+                    .chain([&canonical])
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                self.errors.push(in_file(from, AsmError::CyclicInclude(chain, span)));
+                return
+            }
+            let Ok(content) = std::fs::read_to_string(&canonical) else {
+                self.errors.push(in_file(from, AsmError::IncludeNotFound(cap[1].to_string(), span)));
+                return
+            };
+            let include = Rc::new(Include { path: cap[1].to_string(), at: span, from: from.clone() });
+            let included_dir = canonical.parent().unwrap_or(dir).to_path_buf();
+            self.including.push(canonical);
+            let lines = self.pre_process(&content, &included_dir, Some(include));
+            self.including.pop();
+            self.attach(lables, lines, text, span, from, processed);
+        } else if let Some(mac) = self.macros.get(name).cloned() {
+            let args: Vec<&str> = text.split_whitespace().skip(1).collect();
+            if args.len() != mac.params.len() {
+                self.errors.push(in_file(from, AsmError::WrongNumberOfArgument(mac.params.len(), args.len(), span)));
+                return
+            }
+            if self.depth >= MAX_EXPANSION {
+                self.errors.push(in_file(from, AsmError::TooDeep(name.to_string(), span)));
+                return
+            }
+            self.depth += 1;
+            self.expansions += 1;
+            let id = self.expansions;
+            let word = |word: &str| if word.starts_with("%%") {
+                Some(format!("{}@{}", word, id))
+            } else {
+                mac.params.iter().position(|p| p == word).map(|i| args[i].to_string())
+            };
+            let mut lines = vec![];
+            for body_line in &mac.body {
+                if self.re_empty.is_match(body_line) {
+                    continue
+                }
+                let expanded = match self.re_lable.captures(body_line) {
+                    Some(cap) => format!("{} <- {}",
+                                         self.substitute(&self.re_lable.replace(body_line, ""), word),
+                                         self.substitute(&cap[1], word)),
+                    None => self.substitute(body_line, word),
+                };
+                self.line(&expanded, number, Some(span), dir, from, &mut lines);
+            }
+            self.depth -= 1;
+            self.attach(lables, lines, text, span, from, processed);
+        } else if DIRECTIVES.contains(&name) {
+            self.errors.push(in_file(from, AsmError::InvalidArgument(text.to_string(), span)));
+        } else if name.starts_with('%') && name != "%" {  // % is rem
+            let close = closest(name, DIRECTIVES.iter().copied());
+            self.errors.push(in_file(from, AsmError::UnknownInstruction(name.to_string(), close, span)));
         } else {
-            processed.push(line.to_string());
+            let text = self.substitute(text, |word| self.defines.get(word).cloned());
+            processed.push(Line { number, col: indent + 1, text, lables, call, from: from.clone() });
         }
     }
-    (processed, lable_pool)
-}
-
-
-pub fn compile_to_enum(file_content: String) -> Vec<ByteCode> {
-    let re_push_int   = Regex::new(r"^push (\-?\d+)$").unwrap();
-    let re_push_float = Regex::new(r"^push (\-?\d+.\d+)$").unwrap();
-    let re_push_char  = Regex::new(r"^push '(\w)'$").unwrap();
-    let re_push_str   = Regex::new(r#"^push "(.+)"$"#).unwrap();
-
-    let re_instr_usize =
-        Regex::new(r"^(jmp|pop_jmp_if|pop_jmp_if_not|get|set|call|collect_list) (\d+)$").unwrap();
-    let re_copy = Regex::new(r"^copy -(\d+)$").unwrap();
-    let re_instr_lable = Regex::new(r"^(jmp|pop_jmp_if|pop_jmp_if_not|call) (.+)$").unwrap();
-
-    let mut prog = vec![];
-    let (processed, lable_pool) = pre_process(file_content);
-    for line in processed {
-        let line = &line[..];
-        let current_code = match line {
-            "HALT" => ByteCode::HALT,
-            "pop"  => ByteCode::Pop,
-            "dup"  => ByteCode::Dup,
-            "swap" => ByteCode::Swap,
-            "ret"  => ByteCode::Ret,
-            ">"    => ByteCode::Greater,
-            "<"    => ByteCode::Less,
-            ">="   => ByteCode::GreaterEq,
-            "<="   => ByteCode::LessEq,
-            "=="   => ByteCode::Eq,
-            "!="   => ByteCode::Neq,
-            "==="  => ByteCode::Seq,
-            "!=="  => ByteCode::Sneq,
-            "+"    => ByteCode::Add,
-            "-"    => ByteCode::Sub,
-            "*"    => ByteCode::Mul,
-            "/"    => ByteCode::Div,
-            "%"    => ByteCode::Rem,
-            "++"   => ByteCode::Inc,
-            "--"   => ByteCode::Dec,
-            "&"    => ByteCode::And,
-            "|"    => ByteCode::Or,
-            "!"    => ByteCode::Not,
-            "^"    => ByteCode::Xor,
-            _ => {
-                if re_push_int.is_match(line) {
-                    let cap = re_push_int.captures(line).unwrap();
-                    // the full match is at capture group 0.
-                    let the_int = cap[1].parse::<i64>().unwrap();
-
-                    ByteCode::Push(Value::Int(the_int))
-                } else if re_push_float.is_match(line) {
-                    let cap = re_push_float.captures(line).unwrap();
-                    let the_float = cap[1].parse::<f64>().unwrap();
-
-                    ByteCode::Push(Value::Float(the_float))
-                } else if re_push_char.is_match(line) {
-                    let cap = re_push_char.captures(line).unwrap();
-                    let the_char = cap[1].chars().collect::<Vec<_>>()[0];
-
-                    ByteCode::Push(Value::Char(the_char as u32))
-                } else if re_push_str.is_match(line) {
-                    let cap = re_push_str.captures(line).unwrap();
-                    let the_string = cap[1].to_string();
-
-                    ByteCode::Push(Value::Ref(Rc::new(ObjType::Str(the_string))))
-                } else if re_copy.is_match(line) {
-                    let cap = re_copy.captures(line).unwrap();
-                    let the_usize = cap[1].parse::<usize>().unwrap();
-
-                    ByteCode::Copy(the_usize)
-                } else if re_instr_usize.is_match(line) {
-                    let cap = re_instr_usize.captures(line).unwrap();
-                    let instruction = &cap[1];
-                    let the_usize = cap[2].parse::<usize>().unwrap();
-
-                    match instruction {
-                        "jmp"            => ByteCode::Jmp(the_usize),
-                        "pop_jmp_if"     => ByteCode::PopJmpIf(the_usize),
-                        "pop_jmp_if_not" => ByteCode::PopJmpIfNot(the_usize),
-                        "get"            => ByteCode::Get(the_usize),
-                        "set"            => ByteCode::Set(the_usize),
-                        "call"           => ByteCode::Call(the_usize),
-                        "collect_list"   => ByteCode::CollectList(the_usize),
-                        _                => panic!("[ASM]: Unknown instruction followed by usize")
-                    }
-                } else if re_instr_lable.is_match(line) {
-                    let cap = re_instr_lable.captures(line).unwrap();
-                    let instruction = &cap[1];
-                    let index = *lable_pool.get(&cap[2]).unwrap();
-
-                    match instruction {
-                        "jmp"            => ByteCode::Jmp(index),
-                        "pop_jmp_if"     => ByteCode::PopJmpIf(index),
-                        "pop_jmp_if_not" => ByteCode::PopJmpIfNot(index),
-                        "call"           => ByteCode::Call(index),
-                        _                => todo!()
+
+    // the lables of a line that is a macro or an %include are on the first line it becomes
+    fn attach(&mut self, lables: Vec<(String, Span)>, mut lines: Vec<Line>, text: &str, span: Span,
+              from: &Option<Rc<Include>>, processed: &mut Vec<Line>) {
+        match lines.first_mut() {
+            Some(first) => {
+                let mut lables = lables;
+                lables.append(&mut first.lables);
+                first.lables = lables;
+            }
+            // nothing to put them on
+            None if ! lables.is_empty() => self.errors.push(in_file(from, AsmError::InvalidArgument(text.to_string(), span))),
+            None => (),
+        }
+        processed.extend(lines);
+    }
+}
+
+// the `end` of the function body opened at lines[start]
+fn body_end(lines: &[Line], start: usize, re_push_fn: &Regex) -> Option<usize> {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        if re_push_fn.is_match(&line.text) {
+            depth += 1;
+        } else if line.text == "end" {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i)
+            }
+        }
+    }
+    None
+}
+
+// every function body is a program of its own, with its own lables
+struct Assembler {
+    re_push_int: Regex,
+    re_push_float: Regex,
+    re_push_char: Regex,
+    re_push_str: Regex,
+    re_push_quote: Regex,
+    re_push_fn: Regex,
+    re_copy: Regex,
+    re_instr_usize: Regex,
+    re_instr_lable: Regex,
+    no_arg: HashMap<&'static str, ByteCode>,
+    errors: Vec<AsmError>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let usize_names = WITH_USIZE.iter().map(|(name, _)| *name).collect::<Vec<_>>().join("|");
+        Assembler {
+            re_push_int:   Regex::new(r"^push (\-?\d+)$").unwrap(),
+            re_push_float: Regex::new(r"^push (\-?\d+.\d+)$").unwrap(),
+            re_push_char:  Regex::new(r"^push '(.+)'$").unwrap(),
+            re_push_str:   Regex::new(r#"^push "((?:\\.|[^\\"])*)"$"#).unwrap(),
+            re_push_quote: Regex::new(r"^push '(.+)$").unwrap(),
+            re_push_fn:    Regex::new(r"^push fn (\d+)$").unwrap(),
+            re_copy: Regex::new(r"^copy -?(\d+)$").unwrap(),
+            re_instr_usize: Regex::new(&format!(r"^({}) (\d+)$", usize_names)).unwrap(),
+            re_instr_lable: Regex::new(r"^(jmp|pop_jmp_if|pop_jmp_if_not|call) (.+)$").unwrap(),
+            no_arg: HashMap::from_iter(NO_ARG.iter().cloned()),
+            errors: vec![],
+        }
+    }
+
+    fn error(&mut self, line: &Line, err: AsmError) {
+        self.errors.push(in_file(&line.from, err));
+    }
+
+    fn lables(&mut self, lines: &[Line]) -> HashMap<String, usize> {
+        let mut lable_pool = HashMap::new();
+        let mut index = 0;
+        let mut i = 0;
+        while i < lines.len() {
+            for (lable, span) in &lines[i].lables {
+                if lable_pool.insert(lable.to_owned(), index).is_some() {
+                    self.error(&lines[i], AsmError::DuplicateLable(lable.to_owned(), *span));
+                }
+            }
+            if self.re_push_fn.is_match(&lines[i].text) {
+                // a broken body is reported when it is assembled
+                i = body_end(lines, i, &self.re_push_fn).unwrap_or(lines.len());
+            }
+            index += 1;
+            i += 1;
+        }
+        lable_pool
+    }
+
+    fn assemble(&mut self, lines: &[Line]) -> Vec<ByteCode> {
+        let lable_pool = self.lables(lines);
+        let mut prog = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i];
+            i += 1;
+            let text = &line.text[..];
+            let invalid = || AsmError::InvalidArgument(text.to_string(), line.arg_span());
+            let current_code = if let Some(code) = self.no_arg.get(text) {
+                code.clone()
+            } else if let Some(cap) = self.re_push_fn.captures(text) {
+                let Some(end) = body_end(lines, i - 1, &self.re_push_fn) else {
+                    self.error(line, AsmError::UnclosedFn(line.span()));
+                    break
+                };
+                let body = self.assemble(&lines[i..end]);
+                i = end + 1;
+                match cap[1].parse::<usize>() {
+                    Ok(argc) => ByteCode::Push(Value::Ref(Rc::new(ObjType::Func(argc, body)))),
+                    Err(_) => { self.error(line, invalid()); continue }
+                }
+            } else if text == "end" {
+                self.error(line, AsmError::UnexpectedEnd(line.span()));
+                continue
+            } else if let Some(cap) = self.re_push_int.captures(text) {
+                // the full match is at capture group 0.
+                match cap[1].parse::<i64>() {
+                    Ok(the_int) => ByteCode::Push(Value::Int(the_int)),
+                    Err(_) => { self.error(line, invalid()); continue }
+                }
+            } else if let Some(cap) = self.re_push_float.captures(text) {
+                match cap[1].parse::<f64>() {
+                    Ok(the_float) => ByteCode::Push(Value::Float(the_float)),
+                    Err(_) => { self.error(line, invalid()); continue }
+                }
+            } else if let Some(cap) = self.re_push_char.captures(text) {
+                match literal::unescape_char(&cap[1]) {
+                    Ok(the_char) => ByteCode::Push(Value::Char(the_char as u32)),
+                    Err(err) => { self.error(line, AsmError::InvalidLiteral(err, line.arg_span())); continue }
+                }
+            } else if let Some(cap) = self.re_push_str.captures(text) {
+                match literal::unescape(&cap[1]) {
+                    Ok(the_string) => ByteCode::Push(Value::Ref(Rc::new(ObjType::Str(the_string)))),
+                    Err(err) => { self.error(line, AsmError::InvalidLiteral(err, line.arg_span())); continue }
+                }
+            } else if let Some(cap) = self.re_push_quote.captures(text) {
+                // quoted data written like in the lisp, 'sym or '(1 "a" (b))
+                match parser::parse_program(&cap[1]).as_deref() {
+                    Ok([datum]) => ByteCode::Push(macros::to_value(datum)),
+                    _ => { self.error(line, invalid()); continue }
+                }
+            } else if let Some(cap) = self.re_copy.captures(text) {
+                match cap[1].parse::<usize>() {
+                    Ok(the_usize) => ByteCode::Copy(the_usize),
+                    Err(_) => { self.error(line, invalid()); continue }
+                }
+            } else if let Some(cap) = self.re_instr_usize.captures(text) {
+                let instruction = WITH_USIZE.iter().find(|(name, _)| *name == &cap[1]).unwrap().1;
+                match cap[2].parse::<usize>() {
+                    Ok(the_usize) => instruction(the_usize),
+                    Err(_) => { self.error(line, invalid()); continue }
+                }
+            } else if let Some(cap) = self.re_instr_lable.captures(text) {
+                let instruction = WITH_USIZE.iter().find(|(name, _)| *name == &cap[1]).unwrap().1;
+                match lable_pool.get(&cap[2]) {
+                    Some(index) => instruction(*index),
+                    None => {
+                        let close = closest(&cap[2], lable_pool.keys().map(|k| &k[..]));
+                        self.error(line, AsmError::UndefinedLable(cap[2].to_string(), close, line.arg_span()));
+                        continue
                     }
+                }
+            } else {
+                let name = text.split_whitespace().next().unwrap_or(text);
+                let known = NO_ARG.iter().map(|(name, _)| *name)
+                    .chain(WITH_USIZE.iter().map(|(name, _)| *name))
+                    .chain(["push", "copy"]);
+                if known.clone().any(|known| known == name) {
+                    self.error(line, invalid());
                 } else {
-                    panic!("[ASM]: Unknown instruction {}\n{:?}", line, lable_pool)
+                    let span = Span { len: name.chars().count(), ..line.span() };
+                    self.error(line, AsmError::UnknownInstruction(name.to_string(), closest(name, known), span));
                 }
-            }
-        };
-        prog.push(current_code);
+                continue
+            };
+            prog.push(current_code);
+        }
+        prog
     }
+}
 
-    prog
+// %include is relative to the current directory
+pub fn compile_to_enum(file_content: String) -> Result<Vec<ByteCode>, Vec<AsmError>> {
+    compile(file_content, Path::new("."), None)
 }
 
+// %include is relative to the directory of the file at path
+pub fn compile_file(file_content: String, path: &Path) -> Result<Vec<ByteCode>, Vec<AsmError>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    compile(file_content, dir, path.canonicalize().ok())
+}
+
+fn compile(file_content: String, dir: &Path, path: Option<PathBuf>) -> Result<Vec<ByteCode>, Vec<AsmError>> {
+    let mut pre_processor = PreProcessor::new();
+    pre_processor.including.extend(path);
+    let lines = pre_processor.pre_process(&file_content, dir, None);
+
+    let mut assembler = Assembler::new();
+    assembler.errors = pre_processor.errors;
+    let prog = assembler.assemble(&lines);
+
+    if assembler.errors.is_empty() {
+        Ok(prog)
+    } else {
+        Err(assembler.errors)
+    }
+}
@@ -0,0 +1,208 @@
+use crate::vm::bytecode::ByteCode;
+use crate::vm::value::Value;
+use crate::vm::object::ObjType;
+use crate::frontend::{parser, macros};
+use crate::literal;
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive (Debug)]
+pub enum DisasmError {
+    // the index of the instruction, and the instruction the asm has no syntax for
+    NoSyntax(usize, ByteCode),
+}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisasmError::NoSyntax(index, code) => write!(f, "no asm syntax for {:?} at {}", code, index),
+        }
+    }
+}
+
+fn lable(index: usize) -> String {
+    format!("L{}", index)
+}
+
+// the asm cuts comments and lables off a line first, so they can't be in it
+fn string(s: &str) -> String {
+    format!("\"{}\"", literal::escape(s)
+            .replace(";;", ";\\u{3b}")
+            .replace("<- ", "\\u{3c}- "))
+}
+
+// quoted data the way the lisp writes it
+fn datum(value: &Value) -> Option<String> {
+    let text = match value {
+        Value::Nil => "nil".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => format!("{:?}", f),
+        Value::Char(c) => {
+            let c = char::from_u32(*c)?;
+            match literal::char_name(c) {
+                Some(name) => format!("\\{}", name),
+                None if c.is_control() || c.is_whitespace() => format!("\\u{{{:x}}}", c as u32),
+                None => format!("\\{}", c),
+            }
+        }
+        Value::Ref(rf) => match &**rf {
+            ObjType::Str(s) => string(s),
+            ObjType::Sym(s) => s.to_string(),
+            ObjType::Cons(list) => format!("({})", list.iter().map(datum).collect::<Option<Vec<_>>>()?.join(" ")),
+            _ => return None,
+        },
+    };
+    Some(text)
+}
+
+fn push(value: &Value) -> Option<String> {
+    let text = match value {
+        Value::Nil => "nil".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Int(i) => i.to_string(),
+        // {:?} keeps the `.0`, but writes big and small ones like 1e20
+        Value::Float(f) => {
+            let float = format!("{:?}", f);
+            if ! float.trim_start_matches('-').chars().all(|c| c.is_ascii_digit() || c == '.') {
+                return None
+            }
+            float
+        }
+        Value::Char(c) => format!("'{}'", literal::escape_char(char::from_u32(*c)?)),
+        Value::Ref(rf) => match &**rf {
+            ObjType::Str(s) => string(s),
+            ObjType::Sym(_) | ObjType::Cons(_) => {
+                // read by the lisp parser, which has no syntax for some of them,
+                // a negative int for one, so it must give the same value back
+                let text = datum(value)?;
+                let read = parser::parse(&text).ok().map(|parsed| macros::to_value(&parsed));
+                if text.contains(" <- ") || read.as_ref() != Some(value) {
+                    return None
+                }
+                format!("'{}", text)
+            }
+            _ => return None,
+        },
+    };
+    Some(format!("push {}", text))
+}
+
+fn instruction(code: &ByteCode, targets: &BTreeSet<usize>) -> Option<String> {
+    let jump = |name: &str, to: usize| if targets.contains(&to) {
+        format!("{} {}", name, lable(to))
+    } else {
+        format!("{} {}", name, to)
+    };
+    let line = match code {
+        ByteCode::HALT      => "HALT",
+        ByteCode::Pop       => "pop",
+        ByteCode::Dup       => "dup",
+        ByteCode::Swap      => "swap",
+        ByteCode::Ret       => "ret",
+        ByteCode::Greater   => ">",
+        ByteCode::Less      => "<",
+        ByteCode::GreaterEq => ">=",
+        ByteCode::LessEq    => "<=",
+        ByteCode::Eq        => "==",
+        ByteCode::Neq       => "!=",
+        ByteCode::Seq       => "===",
+        ByteCode::Sneq      => "!==",
+        ByteCode::Add       => "+",
+        ByteCode::Sub       => "-",
+        ByteCode::Mul       => "*",
+        ByteCode::Div       => "/",
+        ByteCode::Rem       => "%",
+        ByteCode::Inc       => "++",
+        ByteCode::Dec       => "--",
+        ByteCode::And       => "&",
+        ByteCode::Or        => "|",
+        ByteCode::Not       => "!",
+        ByteCode::Xor       => "^",
+        ByteCode::Print     => "print",
+        ByteCode::PrintLn   => "println",
+        ByteCode::ReadLine  => "read_line",
+        ByteCode::Exit      => "exit",
+        ByteCode::Car       => "car",
+        ByteCode::Cdr       => "cdr",
+        ByteCode::Cons      => "cons",
+        ByteCode::IsNil     => "is_nil",
+        ByteCode::IsList    => "is_list",
+        ByteCode::NoMatch   => "no_match",
+        ByteCode::Concat    => "concat",
+        ByteCode::Len       => "len",
+        ByteCode::CharAt    => "char_at",
+        ByteCode::Substr    => "substr",
+        ByteCode::StrToList => "str_to_list",
+        ByteCode::ListToStr => "list_to_str",
+        ByteCode::MakeCell  => "make_cell",
+        ByteCode::CellGet   => "cell_get",
+        ByteCode::CellSet   => "cell_set",
+
+        ByteCode::Push(value)  => return push(value),
+        ByteCode::Copy(n)      => return Some(format!("copy -{}", n)),
+        ByteCode::Jmp(to)         => return Some(jump("jmp", *to)),
+        ByteCode::PopJmpIf(to)    => return Some(jump("pop_jmp_if", *to)),
+        ByteCode::PopJmpIfNot(to) => return Some(jump("pop_jmp_if_not", *to)),
+        ByteCode::Call(to)        => return Some(jump("call", *to)),
+        ByteCode::Get(n)             => return Some(format!("get {}", n)),
+        ByteCode::Set(n)             => return Some(format!("set {}", n)),
+        ByteCode::Arg(n)             => return Some(format!("arg {}", n)),
+        ByteCode::LoadLocal(n)       => return Some(format!("load_local {}", n)),
+        ByteCode::StoreLocal(n)      => return Some(format!("store_local {}", n)),
+        ByteCode::LoadCapture(n)     => return Some(format!("load_capture {}", n)),
+        ByteCode::LoadRec(n)         => return Some(format!("load_rec {}", n)),
+        ByteCode::MakeClosure(n)     => return Some(format!("make_closure {}", n)),
+        ByteCode::CallNative(n)      => return Some(format!("call_native {}", n)),
+        ByteCode::CollectList(n)     => return Some(format!("collect_list {}", n)),
+        ByteCode::CollectCharList(n) => return Some(format!("collect_char_list {}", n)),
+        ByteCode::CallTopFn(n)       => return Some(format!("call_top_fn {}", n)),
+        ByteCode::TailCallTopFn(n)   => return Some(format!("tail_call_top_fn {}", n)),
+    };
+    Some(line.to_string())
+}
+
+// the lines of a program, or of a function body `indent` levels deep
+fn lines(program: &[ByteCode], indent: usize, out: &mut Vec<String>) -> Result<(), DisasmError> {
+    let targets: BTreeSet<usize> = program.iter()
+        .filter_map(|code| match code {
+            ByteCode::Jmp(to) | ByteCode::PopJmpIf(to) | ByteCode::PopJmpIfNot(to) | ByteCode::Call(to) => Some(*to),
+            _ => None,
+        })
+        .filter(|to| *to < program.len())
+        .collect();
+    let pad = "    ".repeat(indent);
+
+    for (index, code) in program.iter().enumerate() {
+        let (line, body) = match code {
+            ByteCode::Push(Value::Ref(rf)) => match &**rf {
+                ObjType::Func(argc, body) => (format!("push fn {}", argc), Some(body)),
+                _ => (push(&Value::Ref(rf.clone())).ok_or_else(|| DisasmError::NoSyntax(index, code.clone()))?, None),
+            },
+            _ => (instruction(code, &targets).ok_or_else(|| DisasmError::NoSyntax(index, code.clone()))?, None),
+        };
+        let line = format!("{}{}", pad, line);
+        out.push(if targets.contains(&index) {
+            format!("{:<23} <- {}", line, lable(index))
+        } else {
+            line
+        });
+        if let Some(body) = body {
+            lines(body, indent + 1, out)?;
+            out.push(format!("{}end", pad));
+        }
+    }
+    Ok(())
+}
+
+/// the text `asm::compile_to_enum` turns back into the program,
+/// every target of jmp, pop_jmp_if, pop_jmp_if_not and call inside the program gets a lable.
+/// functions pushed are written as `push fn N`, their bodies, and `end`
+pub fn disassemble(program: &[ByteCode]) -> Result<String, DisasmError> {
+    let mut out = vec![];
+    lines(program, 0, &mut out)?;
+    let mut text = out.join("\n");
+    text.push('\n');
+    Ok(text)
+}
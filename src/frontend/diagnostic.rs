@@ -0,0 +1,38 @@
+use crate::frontend::token::Span;
+
+// rustc style report of an error in the source, e.g.
+//
+// [CODEGEN]: symbol `y` not found
+//  --> repl:1:6
+//   |
+// 1 | (+ 1 y)
+//   |      ^
+pub fn render(source: &str, origin: &str, stage: &str, message: &str, span: Option<Span>) -> String {
+    let span = match span {
+        Some(span) if span.line > 0 => span,
+        _ => return format!("[{}]: {}", stage, message),
+    };
+    let line = source.lines().nth(span.line - 1).unwrap_or("");
+    let number = span.line.to_string();
+    let pad = " ".repeat(number.len());
+    format!("[{}]: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            stage, message,
+            pad, origin, span.line, span.col,
+            pad,
+            number, line,
+            pad, " ".repeat(span.col - 1), "^".repeat(span.len.max(1)))
+}
+
+
+#[cfg (test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let span = Span { line: 2, col: 4, len: 3 };
+        assert_eq!("[CODEGEN]: symbol `foo` not found\n --> a.lisp:2:4\n  |\n2 | (+ foo 1)\n  |    ^^^",
+                   render("(def x 1)\n(+ foo 1)", "a.lisp", "CODEGEN", "symbol `foo` not found", Some(span)));
+        assert_eq!("[PARSER]: nothing to parse", render("", "repl", "PARSER", "nothing to parse", None));
+    }
+}
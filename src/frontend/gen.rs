@@ -1,30 +1,260 @@
 /// generate bytecode from lisp expression
-use crate::frontend::parser::Parsed;
-use crate::frontend::token::Token;
+use crate::frontend::parser::{self, Parsed};
+use crate::frontend::token::{Token, Span};
+use crate::frontend::macros::{self, Macros, MacrosCheckpoint};
 use crate::vm::bytecode::ByteCode;
 use crate::vm::value::Value;
 use crate::vm::object::ObjType;
-use std::collections::HashMap;
+use crate::vm::machine::VM;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use regex::Regex;
+use std::fmt::{Display, Formatter};
 
-#[derive (Debug, Copy, Clone)]
+#[derive (Debug, Clone)]
 pub enum CodeGenError {
-    WrongNumberOfArgument(u8, u8),
-    ArgTypeError,
-    SymbolNotFound,
-    NotValidLambda,
-    IDK,
+    WrongNumberOfArgument(u8, u8, Span),  // expected, got
+    ArgumentOutOfRange(u8, u8, u8, Span),  // at least, at most, got
+    ArgTypeError(Span),
+    SymbolNotFound(String, Span),
+    NotValidLambda(Span),
+    AlreadyDefined(String, Span),
+    MacroAlreadyDefined(String, Span),
+    SetCapture(String, Span),  // captured by value, so it can't be changed
+    NotTopLevel(Span),
+    ModuleNotFound(String, Span),
+    CyclicRequire(String, Span),  // the chain of modules
+    InModule(String, String, Span),  // path, what went wrong in it
+    MacroError(String, Span),
+    IDK(Span),
 }
 
+impl CodeGenError {
+    pub fn span(&self) -> Span {
+        match self {
+            CodeGenError::WrongNumberOfArgument(_, _, span)
+                | CodeGenError::ArgumentOutOfRange(_, _, _, span)
+                | CodeGenError::ArgTypeError(span)
+                | CodeGenError::SymbolNotFound(_, span)
+                | CodeGenError::NotValidLambda(span)
+                | CodeGenError::AlreadyDefined(_, span)
+                | CodeGenError::MacroAlreadyDefined(_, span)
+                | CodeGenError::SetCapture(_, span)
+                | CodeGenError::NotTopLevel(span)
+                | CodeGenError::ModuleNotFound(_, span)
+                | CodeGenError::CyclicRequire(_, span)
+                | CodeGenError::InModule(_, _, span)
+                | CodeGenError::MacroError(_, span)
+                | CodeGenError::IDK(span) => *span,
+        }
+    }
+}
+
+impl Display for CodeGenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodeGenError::WrongNumberOfArgument(expected, got, _) =>
+                write!(f, "expected {} arguments, got {}", expected, got),
+            CodeGenError::ArgumentOutOfRange(min, max, got, _) if max - min == 1 =>
+                write!(f, "expected {} or {} arguments, got {}", min, max, got),
+            CodeGenError::ArgumentOutOfRange(min, max, got, _) =>
+                write!(f, "expected {} to {} arguments, got {}", min, max, got),
+            CodeGenError::ArgTypeError(_) => write!(f, "wrong type of argument"),
+            CodeGenError::SymbolNotFound(sym, _) => write!(f, "symbol `{}` not found", sym),
+            CodeGenError::NotValidLambda(_) => write!(f, "not a valid lambda"),
+            CodeGenError::AlreadyDefined(sym, _) => write!(f, "symbol `{}` is already defined, use set to change it", sym),
+            CodeGenError::MacroAlreadyDefined(sym, _) => write!(f, "macro `{}` is already defined", sym),
+            CodeGenError::SetCapture(sym, _) => write!(f, "symbol `{}` is captured by value and can't be set", sym),
+            CodeGenError::NotTopLevel(_) => write!(f, "only allowed at the top level"),
+            CodeGenError::ModuleNotFound(path, _) => write!(f, "module `{}` not found", path),
+            CodeGenError::CyclicRequire(chain, _) => write!(f, "cyclic require: {}", chain),
+            CodeGenError::InModule(path, err, _) => write!(f, "in module {}: {}", path, err),
+            CodeGenError::MacroError(err, _) => write!(f, "macro expansion failed: {}", err),
+            CodeGenError::IDK(_) => write!(f, "don't know how to generate this"),
+        }
+    }
+}
+
+const MAX_EXPANSION: usize = 256;
+
 // normally last() should't return None, this message is for me when I forgot what's wrong
-const STACK_LAST_ERROR: &'static str = "[CODEGEN]: Env scope stack error";
-const SHOULDNOT_REACH:  &'static str = "[CODEGEN]: Reached unexpected feild";
+const STACK_LAST_ERROR: &str = "[CODEGEN]: Env scope stack error";
+const SHOULDNOT_REACH:  &str = "[CODEGEN]: Reached unexpected feild";
+
+// jump targets are absolute, so a fragment moved to the end of `res` is shifted by where it starts.
+// function bodies are separate programs and are left alone
+fn append(res: &mut Vec<ByteCode>, code: Vec<ByteCode>) {
+    let offset = res.len();
+    res.extend(code.into_iter().map(|byte| match byte {
+        ByteCode::Jmp(pc)         => ByteCode::Jmp(pc + offset),
+        ByteCode::PopJmpIf(pc)    => ByteCode::PopJmpIf(pc + offset),
+        ByteCode::PopJmpIfNot(pc) => ByteCode::PopJmpIfNot(pc + offset),
+        byte => byte,
+    }));
+}
+
+// (fn (args) body), which letrec and def in a block make able to call itself
+fn is_lambda(expr: &Parsed) -> bool {
+    matches!(expr, Parsed::List(list, _) if list.len() == 3 && matches!(list[0], Parsed::Token(Token::Lambda, _)))
+}
+
+fn load_var(var: &Var) -> ByteCode {
+    match var {
+        Var::Arg(i) => ByteCode::Arg(*i),
+        Var::Local(i) => ByteCode::LoadLocal(*i),
+        Var::Capture(i) => ByteCode::LoadCapture(*i),
+        Var::Rec(i) => ByteCode::LoadRec(*i),
+        Var::Global(i) => ByteCode::Get(*i),
+    }
+}
+
+fn any_list<'a>(forms: impl IntoIterator<Item = &'a Parsed>, test: &dyn Fn(&[Parsed]) -> bool) -> bool {
+    forms.into_iter().any(|form| matches!(form, Parsed::List(list, _) if test(list) || any_list(list, test)))
+}
+
+fn mentions(forms: &[Parsed], name: &str) -> bool {
+    forms.iter().any(|form| match form {
+        Parsed::Token(Token::Sym(sym), _) => sym == name,
+        Parsed::Token(..) => false,
+        Parsed::List(list, _) => mentions(list, name),
+    })
+}
+
+// a binding that is set and used in a lambda somewhere in its scope needs a Cell,
+// shadowing is ignored, so a few more than needed get one
+fn needs_cell<'a, I>(name: &str, scope: I) -> bool
+where I: IntoIterator<Item = &'a Parsed> + Clone {
+    let is_name = |parsed: &Parsed| matches!(parsed, Parsed::Token(Token::Sym(sym), _) if sym == name);
+    any_list(scope.clone(), &|list| list.len() > 1 && matches!(list[0], Parsed::Token(Token::Set, _)) && is_name(&list[1]))
+        && any_list(scope, &|list| matches!(list.first(), Some(Parsed::Token(Token::Lambda, _))) && mentions(list, name))
+}
+
+// the names of (def name value) anywhere in forms
+fn defined(forms: &[Parsed], names: &mut HashSet<String>) {
+    for form in forms {
+        if let Parsed::List(list, _) = form {
+            if let [Parsed::Token(Token::Define, _), Parsed::Token(Token::Sym(name), _), ..] = &list[..] {
+                names.insert(name.to_string());
+            }
+            defined(list, names);
+        }
+    }
+}
+
+// quoted data is pushed as it is, symbols and keywords become Sym
+fn quote(data: &Parsed) -> Vec<ByteCode> {
+    vec![ByteCode::Push(macros::to_value(data))]
+}
+
+// the file as it is, or with .lisp
+fn find_module(dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = dir.join(name);
+    [path.clone(), path.with_extension("lisp")].into_iter()
+        .find(|path| path.is_file())
+        .and_then(|path| path.canonicalize().ok())
+}
+
+// `cond` leaves a bool, the clause goes on only if it is not false.
+// the jump is patched when the end of the clause is known
+fn test(code: &mut Vec<ByteCode>, fails: &mut Vec<usize>, cond: Vec<ByteCode>) {
+    append(code, cond);
+    fails.push(code.len());
+    code.push(ByteCode::PopJmpIfNot(0));
+    code.push(ByteCode::Pop);
+}
+
+// a bool of whether what `load` pushes is strictly equal to the literal
+fn equals(load: &[ByteCode], literal: Vec<ByteCode>) -> Vec<ByteCode> {
+    let mut res = load.to_vec();
+    res.extend(literal);
+    res.extend([ByteCode::Seq, ByteCode::Swap, ByteCode::Pop, ByteCode::Swap, ByteCode::Pop]);
+    res
+}
 
 #[derive (Debug)]
 pub struct GenEnv {
     pub sym: Vec<HashMap<String, usize>>,  // 作用域也是通过栈来实现递归
-    pub pool_index: usize
+    pub pool_index: usize,
+    funcs: Vec<FnEnv>,  // the lambdas being generated, innermost last
+    top: Blocks,  // the blocks outside any lambda
+    natives: HashMap<String, (usize, usize)>,  // name -> (index, arity)
+    dir: PathBuf,  // where required paths are relative to
+    modules: HashMap<PathBuf, Vec<(String, usize)>>,  // compiled modules and the globals they provide
+    loading: Vec<PathBuf>,  // the modules being compiled, for cycles
+    provides: Vec<(String, Span)>,  // of the module being compiled
+    macros: Option<Box<Macros>>,  // made by the first defmacro
+    expanding: usize,  // depth of macro expansion
+    cell_defs: Vec<HashSet<String>>,  // the defs of the blocks being generated that need a Cell
+}
+
+// what generating a program changes on the top level, so a program that fails to run can be forgotten
+#[derive (Debug)]
+pub struct Checkpoint {
+    sym: Vec<HashMap<String, usize>>,
+    pool_index: usize,
+    top: Blocks,
+    modules: HashMap<PathBuf, Vec<(String, usize)>>,
+    macros: Option<Box<MacrosCheckpoint>>,  // None before the first defmacro
+}
+
+// free variables from enclosing lambdas in LoadCapture order, and if they are Cells
+type Captures = Vec<(String, bool)>;
+
+// a lambda being generated
+#[derive (Debug)]
+struct FnEnv {
+    params: HashMap<String, Slot>,
+    captures: Captures,
+    recs: usize,  // how many of the captures are the functions of its letrec
+    blocks: Blocks,
+}
+
+// the let and begin blocks being generated in a lambda or the top level.
+// every binding is a local of the frame, after the arguments,
+// so it works in recursive calls and is captured like an argument
+#[derive (Debug, Default, Clone)]
+struct Blocks {
+    scopes: Vec<HashMap<String, Slot>>,  // innermost last
+    next: usize,  // the first free local
+    captured: HashSet<usize>,  // locals and arguments closures copied, setting them would go unseen
+}
+
+// closures capture by value, so a binding that is set and captured is kept in a Cell,
+// the frame and the closures share the Cell and load and store through it
+#[derive (Debug, Clone, Copy)]
+struct Slot {
+    index: usize,
+    def: bool,  // can't be defined again in the block
+    cell: bool,
+}
+
+impl Slot {
+    fn local(index: usize, cell: bool) -> Self {
+        Slot { index, def: false, cell }
+    }
+}
+
+impl Blocks {
+    fn lookup(&self, name: &str) -> Option<(Var, bool)> {
+        self.scopes.iter().rev()
+            .find_map(|scope| scope.get(name))
+            .map(|slot| (Var::Local(slot.index), slot.cell))
+    }
+}
+
+// where a symbol lives
+enum Var {
+    Arg(usize),
+    Local(usize),
+    Capture(usize),
+    Rec(usize),  // a function of the letrec, among the captures
+    Global(usize),
+}
+
+impl Default for GenEnv {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GenEnv {
@@ -32,9 +262,67 @@ impl GenEnv {
         GenEnv {
             sym: vec![HashMap::new()],
             pool_index: 0,
+            funcs: vec![],
+            top: Blocks::default(),
+            natives: HashMap::new(),
+            dir: PathBuf::from("."),
+            modules: HashMap::new(),
+            loading: vec![],
+            provides: vec![],
+            macros: None,
+            expanding: 0,
+            cell_defs: vec![],
         }
     }
 
+    // the directory of the file being compiled
+    pub fn set_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            sym: self.sym.clone(),
+            pool_index: self.pool_index,
+            top: self.top.clone(),
+            modules: self.modules.clone(),
+            macros: self.macros.as_ref().map(|macros| Box::new(macros.checkpoint())),
+        }
+    }
+
+    // the defs after the checkpoint are gone, their slots are set again by the next ones
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.sym = checkpoint.sym;
+        self.pool_index = checkpoint.pool_index;
+        self.top = checkpoint.top;
+        self.modules = checkpoint.modules;
+        self.macros = match (self.macros.take(), checkpoint.macros) {
+            (Some(mut macros), Some(checkpoint)) => {
+                macros.rollback(*checkpoint);
+                Some(macros)
+            }
+            _ => None,  // the first defmacro was after the checkpoint
+        };
+    }
+
+    // make the natives registered on the vm callable by name
+    pub fn bind_natives(&mut self, vm: &VM) {
+        for (index, native) in vm.natives.iter().enumerate() {
+            self.natives.insert(native.name.to_string(), (index, native.arity));
+        }
+    }
+
+    // variables shadow macros too
+    fn is_macro(&mut self, name: &str) -> bool {
+        self.macros.as_ref().is_some_and(|macros| macros.is_macro(name)) && self.resolve(name, self.funcs.len()).is_none()
+    }
+
+    // variables shadow natives
+    fn is_native(&mut self, name: &str) -> bool {
+        self.natives.contains_key(name) && self.resolve(name, self.funcs.len()).is_none()
+    }
+
     pub fn generate_with_halt(&mut self, expr: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
         //println!("{:?}", &expr);
         match self.generate(expr) {
@@ -46,31 +334,30 @@ impl GenEnv {
         }
     }
 
+    // the value of the last form is left on the stack
+    pub fn generate_program(&mut self, forms: &[Parsed]) -> Result<Vec<ByteCode>, CodeGenError> {
+        let mut res = vec![];
+        for (i, form) in forms.iter().enumerate() {
+            if i > 0 {
+                res.push(ByteCode::Pop);
+            }
+            append(&mut res, self.generate(form)?);
+        }
+        res.push(ByteCode::HALT);
+        Ok(res)
+    }
+
     fn generate(&mut self, expr: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        self.generate_in(expr, false)
+    }
+
+    // `tail` is whether the value of expr is returned directly by the lambda
+    fn generate_in(&mut self, expr: &Parsed, tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
         match expr {
-            Parsed::Token(token) => {
+            Parsed::Token(token, span) => {
                 match token {
-                    Token::Sym(s) => {
-                        if s.starts_with("@") {
-                            // for lambda
-                            let re_param_index = Regex::new(r"@(\d+)").unwrap();
-                            let cap = re_param_index.captures(&s).unwrap();
-                            let param_index = cap[1].parse::<usize>().unwrap();
-                            return Ok(vec![ByteCode::Arg(param_index)])
-                        }
-
-                        let mut index = None;
-                        for scope in self.sym.iter().rev() {
-                            if let Some(i) = scope.get(s) {
-                                index = Some(*i);
-                                break;
-                            }
-                        }
-                        match index {
-                            Some(i) => Ok(vec![ByteCode::Get(i)]),
-                            None => Err(CodeGenError::SymbolNotFound),
-                        }
-                    },
+                    Token::Sym(s) => self.load(s, *span),
+                    Token::Nil      => Ok(vec![ByteCode::Push(Value::Nil)]),
                     Token::Bool(b)  => Ok(vec![ByteCode::Push(Value::Bool(*b))]),
                     Token::Int(i)   => Ok(vec![ByteCode::Push(Value::Int(*i))]),
                     Token::Float(f) => Ok(vec![ByteCode::Push(Value::Float(*f))]),
@@ -80,36 +367,115 @@ impl GenEnv {
                             Rc::new(ObjType::Str(s.to_string()))
                         )
                     )]),
-                    _ => todo!("other tokens"),
+                    _ => Err(CodeGenError::IDK(*span)),
                 }
             },
-            Parsed::List(list) => {
+            Parsed::List(list, span) if list.is_empty() => Err(CodeGenError::IDK(*span)),
+            Parsed::List(list, _) => {
                 match &list[0] {
-                    Parsed::Token(Token::List) => {
-                        todo!()
+                    Parsed::Token(Token::List, _) => {
+                        let mut res = vec![];
+                        for i in &list[1..] {
+                            append(&mut res, self.generate(i)?);
+                        }
+                        res.push(ByteCode::CollectList(list.len() - 1));
+                        Ok(res)
+                    }
+                    Parsed::Token(Token::MakeStr, _) => {
+                        // (string \a \b)
+                        let mut res = vec![];
+                        for i in &list[1..] {
+                            append(&mut res, self.generate(i)?);
+                        }
+                        res.push(ByteCode::CollectCharList(list.len() - 1));
+                        Ok(res)
+                    }
+                    Parsed::Token(Token::Quote, _) => {
+                        if list.len() != 2 {
+                            return Err(CodeGenError::WrongNumberOfArgument(1, (list.len() - 1) as u8, expr.span()))
+                        }
+                        Ok(quote(&list[1]))
                     }
-                    Parsed::Token(Token::Quote) => {
-                        todo!()
+                    Parsed::Token(Token::If, _) => self.gen_if(expr, tail),
+                    Parsed::Token(Token::When | Token::Unless, _) => self.gen_when(expr, tail),
+                    Parsed::Token(Token::Cond, _) => self.gen_cond(expr, tail),
+                    Parsed::Token(Token::Let | Token::LetStar | Token::LetRec, _) => self.gen_let(expr, tail),
+                    Parsed::Token(Token::Match, _) => self.gen_match(expr, tail),
+                    Parsed::Token(Token::Require, _) => self.gen_require(expr),
+                    Parsed::Token(Token::Provide, _) => {
+                        for name in &list[1..] {
+                            match name {
+                                Parsed::Token(Token::Sym(sym), span) => self.provides.push((sym.to_string(), *span)),
+                                other => return Err(CodeGenError::ArgTypeError(other.span())),
+                            }
+                        }
+                        Ok(vec![ByteCode::Push(Value::Nil)])
+                    }
+                    Parsed::Token(Token::Begin, _) => {
+                        let next = self.enter_block();
+                        let res = self.sequence(&list[1..], tail);
+                        self.leave_block(next);
+                        res
                     }
-                    Parsed::Token(Token::Sym(_sym)) => {
+                    Parsed::Token(Token::Add | Token::Sub | Token::Mul | Token::Div | Token::Rem |
+                                  Token::And | Token::Or | Token::Xor | Token::StrAppend, _) => self.gen_fold(expr),
+                    Parsed::Token(Token::Greater | Token::GreaterEq | Token::Less | Token::LessEq |
+                                  Token::Eq | Token::Neq | Token::Seq | Token::Sneq, _) => self.gen_compare(expr),
+                    Parsed::Token(Token::DefMacro, _) => {
+                        self.macros.get_or_insert_with(Box::default).define(list, expr.span())?;
+                        Ok(vec![ByteCode::Push(Value::Nil)])
+                    }
+                    Parsed::Token(Token::Sym(sym), _) if self.is_macro(sym) => {
+                        if self.expanding >= MAX_EXPANSION {
+                            return Err(CodeGenError::MacroError(format!("`{}` expands too deep", sym), expr.span()))
+                        }
+                        let macros = self.macros.as_mut().expect(SHOULDNOT_REACH);
+                        let expanded = macros.expand(sym, &list[1..], expr.span())?;
+                        self.expanding += 1;
+                        let res = self.generate_in(&expanded, tail);
+                        self.expanding -= 1;
+                        res
+                    }
+                    Parsed::Token(Token::Quasiquote, _) => {
+                        if list.len() != 2 {
+                            return Err(CodeGenError::WrongNumberOfArgument(1, (list.len() - 1) as u8, expr.span()))
+                        }
+                        self.quasiquote(&list[1])
+                    }
+                    Parsed::Token(Token::Sym(sym), _) if self.is_native(sym) => {
+                        let (index, arity) = self.natives[sym];
+                        if list.len() - 1 != arity {
+                            return Err(CodeGenError::WrongNumberOfArgument(arity as u8, (list.len() - 1) as u8, expr.span()))
+                        }
                         let mut res = vec![];
                         for i in &list[1..] {
-                            res.append(&mut self.generate(&i)?);
+                            append(&mut res, self.generate(i)?);
                         }
-                        res.append(&mut self.generate(&list[0])?);
-                        res.push(ByteCode::CallTopFn);
+                        res.push(ByteCode::CallNative(index));
                         Ok(res)
                     }
-                    Parsed::List(_func) => {
-                        todo!("call direct lambda")
+                    Parsed::Token(Token::Sym(_), _) | Parsed::List(_, _) => {
+                        // call a named function or the result of an expression
+                        let mut res = vec![];
+                        for i in &list[1..] {
+                            append(&mut res, self.generate(i)?);
+                        }
+                        append(&mut res, self.generate(&list[0])?);
+                        let argc = list.len() - 1;
+                        if tail {
+                            res.push(ByteCode::TailCallTopFn(argc));
+                        } else {
+                            res.push(ByteCode::CallTopFn(argc));
+                        }
+                        Ok(res)
                     }
                     _ => {
                         match list.len() - 1 {
-                            0 => todo!(),
-                            1 => self.single_arg(&expr),
-                            2 => self.double_arg(&expr),
-                            3 => todo!(),
-                            _more => todo!(),
+                            0 => self.no_arg(expr),
+                            1 => self.single_arg(expr),
+                            2 => self.double_arg(expr),
+                            3 => self.triple_arg(expr),
+                            _ => Err(CodeGenError::IDK(list[0].span())),
                         }
                     }
                 }
@@ -118,135 +484,897 @@ impl GenEnv {
     }
 
 
-    fn single_arg(&mut self, _expr: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
-        todo!()
+    fn load(&mut self, name: &str, span: Span) -> Result<Vec<ByteCode>, CodeGenError> {
+        let (var, cell) = self.resolve(name, self.funcs.len())
+            .ok_or_else(|| CodeGenError::SymbolNotFound(name.to_string(), span))?;
+        let mut res = vec![load_var(&var)];
+        if cell {
+            res.push(ByteCode::CellGet);
+        }
+        Ok(res)
     }
 
-    fn double_arg(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
-        let expr = if let Parsed::List(expr) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
-        match expr[0] {
-            Parsed::Token(Token::Define) => {
-                if let Parsed::Token(Token::Sym(sym)) = &expr[1] {
-                    let mut val = self.generate(&expr[2])?;
-                    let current_scope = self.sym.last_mut().expect(STACK_LAST_ERROR);
+    // the Cell itself, for a closure to share
+    fn load_captured(&mut self, name: &str) -> Result<ByteCode, CodeGenError> {
+        self.resolve(name, self.funcs.len())
+            .map(|(var, _)| load_var(&var))
+            .ok_or_else(|| CodeGenError::SymbolNotFound(name.to_string(), Span::default()))
+    }
 
-                    if current_scope.contains_key(sym) {
-                        // redefine
-                        let index = *current_scope.get(sym).unwrap();
-                        val.push(ByteCode::Set(index));
-                        val.push(ByteCode::Get(index));  // should return the value
-                        Ok(val)
+    // look up from the lambda at `level` (0 is the top level) outwards, and whether it's a Cell.
+    // a variable of an enclosing lambda is captured by every lambda in between
+    fn resolve(&mut self, name: &str, level: usize) -> Option<(Var, bool)> {
+        if level == 0 {
+            if let Some(found) = self.top.lookup(name) {
+                return Some(found)
+            }
+            return self.sym.iter().rev()
+                .find_map(|scope| scope.get(name))
+                .map(|i| (Var::Global(*i), false))
+        }
+        let func = &self.funcs[level - 1];
+        if let Some(found) = func.blocks.lookup(name) {
+            return Some(found)
+        }
+        if let Some(slot) = func.params.get(name) {
+            return Some((Var::Arg(slot.index), slot.cell))
+        }
+        if let Some(i) = func.captures.iter().position(|(c, _)| c == name) {
+            let var = if i < func.recs { Var::Rec(i) } else { Var::Capture(i) };
+            return Some((var, func.captures[i].1))
+        }
+        match self.resolve(name, level - 1)? {
+            (Var::Global(i), _) => Some((Var::Global(i), false)),
+            (var, cell) => {
+                if let Var::Arg(i) | Var::Local(i) = var {
+                    self.blocks_at(level - 1).captured.insert(i);
+                }
+                let captures = &mut self.funcs[level - 1].captures;
+                captures.push((name.to_string(), cell));
+                Some((Var::Capture(captures.len() - 1), cell))
+            }
+        }
+    }
+
+    fn blocks_at(&mut self, level: usize) -> &mut Blocks {
+        match level {
+            0 => &mut self.top,
+            level => &mut self.funcs[level - 1].blocks,
+        }
+    }
+
+    fn blocks(&mut self) -> &mut Blocks {
+        self.blocks_at(self.funcs.len())
+    }
+
+    // returns the first free local, to be given back to leave_block
+    fn enter_block(&mut self) -> usize {
+        let blocks = self.blocks();
+        blocks.scopes.push(HashMap::new());
+        blocks.next
+    }
+
+    // the locals of the block are free again
+    fn leave_block(&mut self, next: usize) {
+        let blocks = self.blocks();
+        blocks.scopes.pop().expect(STACK_LAST_ERROR);
+        blocks.next = next;
+    }
+
+    fn bind(&mut self, name: &str, slot: Slot) -> Option<Slot> {
+        let blocks = self.blocks();
+        blocks.captured.remove(&slot.index);  // a slot of a block that is over
+        blocks.scopes.last_mut().expect(STACK_LAST_ERROR).insert(name.to_string(), slot)
+    }
+
+    fn alloc_local(&mut self) -> usize {
+        let blocks = self.blocks();
+        blocks.next += 1;
+        blocks.next - 1
+    }
+
+    fn alloc_global(&mut self) -> usize {
+        self.pool_index += 1;
+        self.pool_index - 1
+    }
+
+    // like def at the top level, but the name is only seen in the block
+    fn define_in_block(&mut self, name: &str, span: Span, value: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let previous = self.blocks().scopes.last().expect(STACK_LAST_ERROR).get(name).copied();
+        if previous.is_some_and(|slot| slot.def) {
+            return Err(CodeGenError::AlreadyDefined(name.to_string(), span))
+        }
+        // may shadow a let binding of the same block.
+        // a function is never a Cell, it is captured by the functions of its own letrec
+        let cell = ! is_lambda(value) && self.cell_defs.last().is_some_and(|defs| defs.contains(name));
+        let slot = self.alloc_local();
+        self.bind(name, Slot { index: slot, def: true, cell });
+        let val = if is_lambda(value) {
+            self.gen_recs(&[(name, value)])
+                .and_then(|(funcs, captures)| self.close(funcs[0].clone(), &captures, &funcs))
+        } else {
+            self.generate(value)
+        };
+        if val.is_err() {
+            let scope = self.blocks().scopes.last_mut().expect(STACK_LAST_ERROR);
+            match previous {
+                Some(slot) => scope.insert(name.to_string(), slot),
+                None => scope.remove(name),
+            };
+        }
+        let mut val = val?;
+        if cell {
+            val.push(ByteCode::Dup);
+            val.push(ByteCode::MakeCell);
+            val.push(ByteCode::StoreLocal(slot));
+        } else {
+            val.push(ByteCode::StoreLocal(slot));
+            val.push(ByteCode::LoadLocal(slot));
+        }
+        Ok(val)
+    }
+
+    // (let ((name value) ...) body...), let* sees the names bound before,
+    // letrec binds all of them first
+    fn gen_let(&mut self, list: &Parsed, tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        if expr.len() < 2 {
+            return Err(CodeGenError::WrongNumberOfArgument(1, 0, list.span()))
+        }
+        let bindings = match &expr[1] {
+            Parsed::List(bindings, _) => bindings,
+            other => return Err(CodeGenError::ArgTypeError(other.span())),
+        };
+        let mut pairs = vec![];
+        for binding in bindings {
+            match binding {
+                Parsed::List(pair, _) if pair.len() == 2 => match &pair[0] {
+                    Parsed::Token(Token::Sym(name), _) => pairs.push((name.as_str(), &pair[1])),
+                    other => return Err(CodeGenError::ArgTypeError(other.span())),
+                },
+                other => return Err(CodeGenError::ArgTypeError(other.span())),
+            }
+        }
+
+        let next = self.enter_block();
+        let res = self.gen_bindings(&expr[0], &pairs, &expr[2..], tail);
+        self.leave_block(next);
+        res
+    }
+
+    fn gen_bindings(&mut self, kind: &Parsed, pairs: &[(&str, &Parsed)], body: &[Parsed], tail: bool)
+                    -> Result<Vec<ByteCode>, CodeGenError> {
+        let mut res = vec![];
+        let scope = || pairs.iter().map(|(_, value)| *value).chain(body);
+        let store = |res: &mut Vec<ByteCode>, slot: Slot| {
+            if slot.cell {
+                res.push(ByteCode::MakeCell);
+            }
+            res.push(ByteCode::StoreLocal(slot.index));
+        };
+        match kind {
+            Parsed::Token(Token::Let, _) => {
+                // the values can't see the names, so they are bound after all of them
+                let mut slots = vec![];
+                for (name, value) in pairs {
+                    append(&mut res, self.generate(value)?);
+                    let slot = Slot::local(self.alloc_local(), needs_cell(name, scope()));
+                    store(&mut res, slot);
+                    slots.push(slot);
+                }
+                for ((name, _), slot) in pairs.iter().zip(slots) {
+                    self.bind(name, slot);
+                }
+            }
+            Parsed::Token(Token::LetStar, _) => {
+                for (name, value) in pairs {
+                    append(&mut res, self.generate(value)?);
+                    let slot = Slot::local(self.alloc_local(), needs_cell(name, scope()));
+                    store(&mut res, slot);
+                    self.bind(name, slot);
+                }
+            }
+            _ => {
+                // all the names are bound first, the functions are closed over each other
+                let slots: Vec<Slot> = pairs.iter().map(|(name, value)| {
+                    let slot = Slot::local(self.alloc_local(), ! is_lambda(value) && needs_cell(name, scope()));
+                    self.bind(name, slot);
+                    slot
+                }).collect();
+                let recs: Vec<(&str, &Parsed)> = pairs.iter().filter(|(_, value)| is_lambda(value)).copied().collect();
+                let (funcs, captures) = self.gen_recs(&recs)?;
+                let mut funcs_left = funcs.iter();
+                for ((_, value), slot) in pairs.iter().zip(slots) {
+                    if is_lambda(value) {
+                        let function = funcs_left.next().expect(SHOULDNOT_REACH).clone();
+                        append(&mut res, self.close(function, &captures, &funcs)?);
                     } else {
-                        current_scope
-                            .insert(sym.to_string(), self.pool_index);
-                        val.push(ByteCode::Set(self.pool_index));
-                        val.push(ByteCode::Get(self.pool_index));  // should return the value
-                        self.pool_index += 1;
-                        Ok(val)
+                        append(&mut res, self.generate(value)?);
                     }
-                } else {
-                    Err(CodeGenError::ArgTypeError)
-                }
-            }
-
-            // arithmetic operation
-            Parsed::Token(Token::Add | Token::Sub | Token::Mul | Token:: Div | Token::Rem) => {
-                match (&expr[1], &expr[2]) {
-                    (Parsed::Token(Token::Int(_) | Token::Float(_) | Token::Char(_) | Token::Sym(_)) | Parsed::List(_),
-                     Parsed::Token(Token::Int(_) | Token::Float(_) | Token::Char(_) | Token::Sym(_)) | Parsed::List(_)) => {
-                        let token = if let Parsed::Token(token) = &expr[0] { token } else { todo!("{}", SHOULDNOT_REACH) };
-                        let operator = match token {
-                            Token::Add => ByteCode::Add,
-                            Token::Sub => ByteCode::Sub,
-                            Token::Mul => ByteCode::Mul,
-                            Token::Div => ByteCode::Div,
-                            Token::Rem => ByteCode::Rem,
-                            _ => todo!("{}", SHOULDNOT_REACH)
-                        };
-                        let mut res = vec![];
-                        res.append(&mut self.generate(&expr[1])?);
-                        res.append(&mut self.generate(&expr[2])?);
-                        res.push(operator);
-                        Ok(res)
+                    store(&mut res, slot);
+                }
+            }
+        }
+        append(&mut res, self.sequence(body, tail)?);
+        Ok(res)
+    }
+
+    // (match value (pattern body...) (pattern when guard body...) ...)
+    // the value is kept in a local, each clause tests it and jumps to the next one if it fails.
+    // built from the last clause backwards, like cond, the end is NoMatch
+    fn gen_match(&mut self, list: &Parsed, tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        if expr.len() < 2 {
+            return Err(CodeGenError::WrongNumberOfArgument(1, 0, list.span()))
+        }
+        let mut clauses = vec![];
+        for clause in &expr[2..] {
+            match clause {
+                Parsed::List(clause, _) if clause.len() >= 3 && matches!(clause[1], Parsed::Token(Token::When, _)) =>
+                    clauses.push((&clause[0], Some(&clause[2]), &clause[3..])),
+                Parsed::List(clause, _) if ! clause.is_empty() =>
+                    clauses.push((&clause[0], None, &clause[1..])),
+                other => return Err(CodeGenError::ArgTypeError(other.span())),
+            }
+        }
+
+        let mut res = self.generate(&expr[1])?;
+        let next = self.enter_block();
+        let value = self.alloc_local();
+        res.push(ByteCode::StoreLocal(value));
+        let clauses = self.gen_clauses(value, &clauses, tail);
+        self.leave_block(next);
+        append(&mut res, clauses?);
+        Ok(res)
+    }
+
+    fn gen_clauses(&mut self, value: usize, clauses: &[(&Parsed, Option<&Parsed>, &[Parsed])], tail: bool)
+                   -> Result<Vec<ByteCode>, CodeGenError> {
+        let mut rest = vec![ByteCode::LoadLocal(value), ByteCode::NoMatch];
+        for (pattern, guard, body) in clauses.iter().rev() {
+            let next = self.enter_block();
+            let clause = self.gen_clause(value, pattern, *guard, body, tail);
+            self.leave_block(next);
+            let (mut code, fails) = clause?;
+
+            // a failed test goes to the next clause, the body jumps over all of them
+            let next_clause = code.len() + 1;
+            for fail in fails {
+                code[fail] = ByteCode::PopJmpIfNot(next_clause);
+            }
+            code.push(ByteCode::Jmp(next_clause + rest.len()));
+            append(&mut code, rest);
+            rest = code;
+        }
+        Ok(rest)
+    }
+
+    // the code of a clause without the jump to the end, and where its tests jump when they fail
+    fn gen_clause(&mut self, value: usize, pattern: &Parsed, guard: Option<&Parsed>, body: &[Parsed], tail: bool)
+                  -> Result<(Vec<ByteCode>, Vec<usize>), CodeGenError> {
+        let mut code = vec![];
+        let mut fails = vec![];
+        let scope: Vec<&Parsed> = guard.into_iter().chain(body).collect();
+        self.gen_pattern(pattern, &scope, &mut vec![ByteCode::LoadLocal(value)], &mut code, &mut fails)?;
+        if let Some(guard) = guard {
+            let guard = self.generate(guard)?;
+            test(&mut code, &mut fails, guard);
+        }
+        append(&mut code, self.sequence(body, tail)?);
+        Ok((code, fails))
+    }
+
+    // `load` pushes the part of the value the pattern is matched against, `scope` is where its names are seen
+    fn gen_pattern(&mut self, pattern: &Parsed, scope: &[&Parsed], load: &mut Vec<ByteCode>, code: &mut Vec<ByteCode>,
+                   fails: &mut Vec<usize>) -> Result<(), CodeGenError> {
+        match pattern {
+            Parsed::Token(Token::Sym(name), _) if name == "_" => (),
+            Parsed::Token(Token::Sym(name), _) => {
+                let slot = Slot::local(self.alloc_local(), needs_cell(name, scope.iter().copied()));
+                code.extend(load.iter().cloned());
+                if slot.cell {
+                    code.push(ByteCode::MakeCell);
+                }
+                code.push(ByteCode::StoreLocal(slot.index));
+                self.bind(name, slot);
+            }
+            Parsed::Token(Token::Nil | Token::Bool(_) | Token::Int(_) | Token::Float(_) | Token::Char(_) | Token::Str(_), _) => {
+                test(code, fails, equals(load, quote(pattern)));
+            }
+            Parsed::List(list, _) if list.len() == 2 && matches!(list[0], Parsed::Token(Token::Quote, _)) => {
+                test(code, fails, equals(load, quote(&list[1])));
+            }
+            Parsed::List(list, _) => {
+                // (a b) has exactly two elements, (a b . rest) at least two
+                let dot = list.iter().position(|p| matches!(p, Parsed::Token(Token::Sym(s), _) if s == "."));
+                let (heads, rest) = match dot {
+                    Some(dot) if dot + 2 == list.len() => (&list[..dot], Some(&list[dot + 1])),
+                    Some(dot) => return Err(CodeGenError::ArgTypeError(list[dot].span())),
+                    None => (&list[..], None),
+                };
+
+                let mut is_list = load.clone();
+                is_list.push(ByteCode::IsList);
+                test(code, fails, is_list);
+                let mut len = load.clone();
+                len.push(ByteCode::Len);
+                len.push(ByteCode::Push(Value::Int(heads.len() as i64)));
+                len.push(if rest.is_some() { ByteCode::GreaterEq } else { ByteCode::Eq });
+                len.extend([ByteCode::Swap, ByteCode::Pop, ByteCode::Swap, ByteCode::Pop]);
+                test(code, fails, len);
+
+                let depth = load.len();
+                for head in heads {
+                    load.push(ByteCode::Car);
+                    self.gen_pattern(head, scope, load, code, fails)?;
+                    load.pop();
+                    load.push(ByteCode::Cdr);
+                }
+                if let Some(rest) = rest {
+                    self.gen_pattern(rest, scope, load, code, fails)?;
+                }
+                load.truncate(depth);
+            }
+            other => return Err(CodeGenError::ArgTypeError(other.span())),
+        }
+        Ok(())
+    }
+
+    // (require "path") runs the module the first time, and binds the names it provides.
+    // a module has its own global scope, only the provided names are seen by the requirer.
+    // the scope is only a compile time one, its globals are slots of the same pool as everyone's
+    fn gen_require(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        if ! self.funcs.is_empty() || ! self.top.scopes.is_empty() {
+            return Err(CodeGenError::NotTopLevel(list.span()))
+        }
+        let (name, span) = match expr.get(1) {
+            Some(Parsed::Token(Token::Str(name), span)) if expr.len() == 2 => (name, *span),
+            Some(other) if expr.len() == 2 => return Err(CodeGenError::ArgTypeError(other.span())),
+            _ => return Err(CodeGenError::WrongNumberOfArgument(1, (expr.len() - 1) as u8, list.span())),
+        };
+        let path = find_module(&self.dir, name)
+            .ok_or_else(|| CodeGenError::ModuleNotFound(name.to_string(), span))?;
+        if let Some(start) = self.loading.iter().position(|loading| *loading == path) {
+            let chain: Vec<String> = self.loading[start..].iter().chain([&path])
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(CodeGenError::CyclicRequire(chain.join(" -> "), span))
+        }
+
+        let mut res = vec![];
+        if ! self.modules.contains_key(&path) {
+            let code = self.gen_module(&path, span)?;
+            append(&mut res, code);
+        }
+        for (name, index) in self.modules[&path].clone() {
+            let scope = self.sym.last_mut().expect(STACK_LAST_ERROR);
+            if scope.get(&name).is_some_and(|i| *i != index) {
+                return Err(CodeGenError::AlreadyDefined(name, span))
+            }
+            scope.insert(name, index);
+        }
+        res.push(ByteCode::Push(Value::Nil));
+        Ok(res)
+    }
+
+    fn gen_module(&mut self, path: &Path, span: Span) -> Result<Vec<ByteCode>, CodeGenError> {
+        let in_module = |err: String| CodeGenError::InModule(path.display().to_string(), err, span);
+        let source = std::fs::read_to_string(path).map_err(|err| in_module(err.to_string()))?;
+        let forms = parser::parse_program(&source).map_err(|err| match err.span() {
+            Some(at) => in_module(format!("{}:{}: {}", at.line, at.col, err)),
+            None => in_module(err.to_string()),
+        })?;
+
+        let sym = std::mem::replace(&mut self.sym, vec![HashMap::new()]);
+        let provides = std::mem::take(&mut self.provides);
+        let dir = std::mem::replace(&mut self.dir, path.parent().map(Path::to_path_buf).unwrap_or_default());
+        self.loading.push(path.to_path_buf());
+
+        let mut code = vec![];
+        let mut failed = None;
+        for form in &forms {
+            match self.generate(form) {
+                Ok(form) => {
+                    append(&mut code, form);
+                    code.push(ByteCode::Pop);
+                }
+                Err(err) => {
+                    failed = Some(err);
+                    break
+                }
+            }
+        }
+
+        self.loading.pop();
+        self.dir = dir;
+        let module_provides = std::mem::replace(&mut self.provides, provides);
+        let module_sym = std::mem::replace(&mut self.sym, sym);
+
+        if let Some(err) = failed {
+            let at = err.span();
+            return Err(in_module(format!("{}:{}: {}", at.line, at.col, err)))
+        }
+        let mut exports = vec![];
+        for (name, at) in module_provides {
+            match module_sym[0].get(&name) {
+                Some(index) => exports.push((name, *index)),
+                None => return Err(in_module(format!("{}:{}: {}", at.line, at.col,
+                                                     CodeGenError::SymbolNotFound(name, at)))),
+            }
+        }
+        self.modules.insert(path.to_path_buf(), exports);
+        Ok(code)
+    }
+
+    // like quote, but ,x is the value of x and ,@x splices the list x in.
+    // the elements between splices are collected into lists, and all of them are concatenated
+    fn quasiquote(&mut self, data: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let list = match data {
+            Parsed::List(list, _) if list.len() == 2 && matches!(list[0], Parsed::Token(Token::Unquote, _)) => {
+                return self.generate(&list[1])
+            }
+            Parsed::List(list, _) => list,
+            _ => return Ok(quote(data)),
+        };
+
+        let mut res = vec![];
+        let mut parts = 0;
+        let mut collected = 0;
+        // each finished part is concatenated to the ones before it
+        let end_part = |res: &mut Vec<ByteCode>, parts: &mut usize| {
+            *parts += 1;
+            if *parts > 1 {
+                res.push(ByteCode::Concat);
+            }
+        };
+        for elem in list {
+            match elem {
+                Parsed::List(splice, _) if splice.len() == 2 && matches!(splice[0], Parsed::Token(Token::UnquoteSplicing, _)) => {
+                    if collected > 0 {
+                        res.push(ByteCode::CollectList(collected));
+                        end_part(&mut res, &mut parts);
+                        collected = 0;
                     }
-                    _ => Err(CodeGenError::ArgTypeError)
+                    append(&mut res, self.generate(&splice[1])?);
+                    end_part(&mut res, &mut parts);
+                }
+                _ => {
+                    append(&mut res, self.quasiquote(elem)?);
+                    collected += 1;
                 }
             }
+        }
+        if collected > 0 || parts == 0 {
+            res.push(ByteCode::CollectList(collected));
+            end_part(&mut res, &mut parts);
+        }
+        Ok(res)
+    }
 
-            // logical operation
-            Parsed::Token(Token::And | Token::Or | Token::Xor) => {
-                match (&expr[1], &expr[2]) {
-                    (Parsed::Token(Token::Int(_) | Token::Bool(_) | Token::Char(_) | Token::Sym(_)) | Parsed::List(_),
-                     Parsed::Token(Token::Int(_) | Token::Bool(_) | Token::Char(_) | Token::Sym(_)) | Parsed::List(_)) => {
-                        let token = if let Parsed::Token(token) = &expr[0] { token } else { todo!("{}", SHOULDNOT_REACH) };
-                        let operator = match token {
-                            Token::And => ByteCode::And,
-                            Token::Or  => ByteCode::Or,
-                            Token::Xor => ByteCode::Xor,
-                            _ => todo!("{}", SHOULDNOT_REACH)
-                        };
-                        let mut res = vec![];
-                        res.append(&mut self.generate(&expr[1])?);
-                        res.append(&mut self.generate(&expr[2])?);
-                        res.push(operator);
-                        Ok(res)
+    // evaluate forms in order and keep the value of the last one, nil if there is none
+    fn sequence(&mut self, forms: &[Parsed], tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
+        if forms.is_empty() {
+            return Ok(vec![ByteCode::Push(Value::Nil)])
+        }
+        let mut defs = HashSet::new();
+        defined(forms, &mut defs);
+        defs.retain(|name| needs_cell(name, forms));
+        self.cell_defs.push(defs);
+        let mut res = vec![];
+        for (i, form) in forms.iter().enumerate() {
+            if i > 0 {
+                res.push(ByteCode::Pop);
+            }
+            match self.generate_in(form, tail && i == forms.len() - 1) {
+                Ok(code) => append(&mut res, code),
+                Err(err) => {
+                    self.cell_defs.pop();
+                    return Err(err)
+                }
+            }
+        }
+        self.cell_defs.pop();
+        Ok(res)
+    }
+
+    // everything except false is true.
+    // PopJmpIfNot only pops when it jumps, so the taken branch pops the condition itself:
+    //     cond; PopJmpIfNot(else); Pop; then; Jmp(end); else: otherwise; end:
+    fn branch(&mut self, cond: &Parsed, then: Vec<ByteCode>, otherwise: Vec<ByteCode>) -> Result<Vec<ByteCode>, CodeGenError> {
+        let mut res = self.generate(cond)?;
+        let then_start = res.len() + 2;
+        let else_start = then_start + then.len() + 1;
+        res.push(ByteCode::PopJmpIfNot(else_start));
+        res.push(ByteCode::Pop);
+        append(&mut res, then);
+        res.push(ByteCode::Jmp(else_start + otherwise.len()));
+        append(&mut res, otherwise);
+        Ok(res)
+    }
+
+    // (if cond then) or (if cond then else)
+    fn gen_if(&mut self, list: &Parsed, tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        if expr.len() != 3 && expr.len() != 4 {
+            return Err(CodeGenError::ArgumentOutOfRange(2, 3, (expr.len() - 1) as u8, list.span()))
+        }
+        let then = self.generate_in(&expr[2], tail)?;
+        let otherwise = match expr.get(3) {
+            Some(otherwise) => self.generate_in(otherwise, tail)?,
+            None => vec![ByteCode::Push(Value::Nil)],
+        };
+        self.branch(&expr[1], then, otherwise)
+    }
+
+    // (when cond body...) and (unless cond body...)
+    fn gen_when(&mut self, list: &Parsed, tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        if expr.len() < 2 {
+            return Err(CodeGenError::WrongNumberOfArgument(1, 0, list.span()))
+        }
+        let body = self.sequence(&expr[2..], tail)?;
+        let nil = vec![ByteCode::Push(Value::Nil)];
+        if matches!(expr[0], Parsed::Token(Token::When, _)) {
+            self.branch(&expr[1], body, nil)
+        } else {
+            self.branch(&expr[1], nil, body)
+        }
+    }
+
+    // (cond (test body...) ... (else body...)), nil if no test is true
+    fn gen_cond(&mut self, list: &Parsed, tail: bool) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        let clauses = &expr[1..];
+        for (i, clause) in clauses.iter().enumerate() {
+            match clause {
+                Parsed::List(clause, _) if ! clause.is_empty() => {
+                    if matches!(clause[0], Parsed::Token(Token::Else, _)) && i != clauses.len() - 1 {
+                        // else must be the last clause
+                        return Err(CodeGenError::ArgTypeError(clause[0].span()))
                     }
-                    _ => Err(CodeGenError::ArgTypeError)
                 }
+                _ => return Err(CodeGenError::ArgTypeError(clause.span())),
             }
+        }
 
-            Parsed::Token(Token::Lambda) => {
-                let argv = if let Parsed::List(argv) = &expr[1] { argv } else { return Err(CodeGenError::NotValidLambda) };
-                let body = &expr[2];
-                {
-                    // checks if argv are all symbol
-                    let mut valid = true;
-                    for arg in argv {
-                        match arg {
-                            Parsed::Token(Token::Sym(_)) => (),
-                            _ => valid = false,
-                        }
+        // built from the last clause backwards, each clause falls through to the ones after it
+        let mut res = vec![ByteCode::Push(Value::Nil)];
+        for clause in clauses.iter().rev() {
+            let clause = if let Parsed::List(clause, _) = clause { clause } else { todo!("{}", SHOULDNOT_REACH) };
+            let body = self.sequence(&clause[1..], tail)?;
+            res = if matches!(clause[0], Parsed::Token(Token::Else, _)) {
+                body
+            } else {
+                self.branch(&clause[0], body, res)?
+            };
+        }
+        Ok(res)
+    }
+
+    // (+ a b c) is ((a + b) + c), and (- a) is a * -1
+    fn gen_fold(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        let token = if let Parsed::Token(token, _) = &expr[0] { token } else { todo!("{}", SHOULDNOT_REACH) };
+        let operator = match token {
+            Token::Add => ByteCode::Add,
+            Token::Sub => ByteCode::Sub,
+            Token::Mul => ByteCode::Mul,
+            Token::Div => ByteCode::Div,
+            Token::Rem => ByteCode::Rem,
+            Token::And => ByteCode::And,
+            Token::Or  => ByteCode::Or,
+            Token::Xor => ByteCode::Xor,
+            Token::StrAppend => ByteCode::Concat,
+            _ => todo!("{}", SHOULDNOT_REACH)
+        };
+        let logical = matches!(token, Token::And | Token::Or | Token::Xor);
+        let string = matches!(token, Token::StrAppend);
+        let operands = &expr[1..];
+        for operand in operands {
+            let valid = match operand {
+                Parsed::Token(Token::Sym(_), _) | Parsed::List(_, _) => true,
+                Parsed::Token(Token::Str(_), _) => string,
+                Parsed::Token(Token::Int(_) | Token::Char(_), _) => ! string,
+                Parsed::Token(Token::Float(_), _) => ! logical && ! string,
+                Parsed::Token(Token::Bool(_), _) => logical,
+                _ => false,
+            };
+            if ! valid {
+                return Err(CodeGenError::ArgTypeError(operand.span()))
+            }
+        }
+
+        let mut res = vec![];
+        match (token, operands.len()) {
+            (Token::Sub, 1) => {
+                append(&mut res, self.generate(&operands[0])?);
+                res.push(ByteCode::Push(Value::Int(-1)));
+                res.push(ByteCode::Mul);
+            }
+            (Token::Rem, n) if n != 2 => {
+                return Err(CodeGenError::WrongNumberOfArgument(2, n as u8, list.span()))
+            }
+            (_, n) if n < 2 => {
+                return Err(CodeGenError::WrongNumberOfArgument(2, n as u8, list.span()))
+            }
+            _ => {
+                append(&mut res, self.generate(&operands[0])?);
+                for operand in &operands[1..] {
+                    append(&mut res, self.generate(operand)?);
+                    res.push(operator.clone());
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // the comparison instructions keep both operands, they are dropped below the result:
+    //     a; b; Greater; Swap; Pop; Swap; Pop
+    // a chain like (< a b c) compares copies of each neighbouring pair,
+    // ands the results, and then drops the operands
+    fn gen_compare(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        let operator = match &expr[0] {
+            Parsed::Token(Token::Greater, _)   => ByteCode::Greater,
+            Parsed::Token(Token::GreaterEq, _) => ByteCode::GreaterEq,
+            Parsed::Token(Token::Less, _)      => ByteCode::Less,
+            Parsed::Token(Token::LessEq, _)    => ByteCode::LessEq,
+            Parsed::Token(Token::Eq, _)        => ByteCode::Eq,
+            Parsed::Token(Token::Neq, _)       => ByteCode::Neq,
+            Parsed::Token(Token::Seq, _)       => ByteCode::Seq,
+            Parsed::Token(Token::Sneq, _)      => ByteCode::Sneq,
+            _ => todo!("{}", SHOULDNOT_REACH)
+        };
+        let operands = &expr[1..];
+        if operands.len() < 2 {
+            return Err(CodeGenError::WrongNumberOfArgument(2, operands.len() as u8, list.span()))
+        }
+        // removes n values under the top one
+        let drop_below = |res: &mut Vec<ByteCode>, n| {
+            for _ in 0..n {
+                res.push(ByteCode::Swap);
+                res.push(ByteCode::Pop);
+            }
+        };
+
+        let mut res = vec![];
+        for operand in operands {
+            append(&mut res, self.generate(operand)?);
+        }
+        if operands.len() == 2 {
+            res.push(operator);
+            drop_below(&mut res, 2);
+            return Ok(res)
+        }
+
+        for k in 0..operands.len() - 1 {
+            // the pair is at the same depth, the first copy pushes the second one down
+            let depth = operands.len() - k - 1 + usize::from(k > 0);
+            res.push(ByteCode::Copy(depth));
+            res.push(ByteCode::Copy(depth));
+            res.push(operator.clone());
+            drop_below(&mut res, 2);
+            if k > 0 {
+                res.push(ByteCode::And);
+            }
+        }
+        drop_below(&mut res, operands.len());
+        Ok(res)
+    }
+
+    fn no_arg(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        match expr[0] {
+            Parsed::Token(Token::Newline, _) => Ok(vec![
+                ByteCode::Push(Value::Char('\n' as u32)),
+                ByteCode::Print,
+                ByteCode::Push(Value::Nil),
+            ]),
+            Parsed::Token(Token::ReadLine, _) => Ok(vec![ByteCode::ReadLine]),
+            _ => Err(CodeGenError::IDK(expr[0].span())),
+        }
+    }
+
+    fn single_arg(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        match expr[0] {
+            Parsed::Token(Token::Display | Token::DisplayLn, _) => {
+                let mut res = self.generate(&expr[1])?;
+                if matches!(expr[0], Parsed::Token(Token::Display, _)) {
+                    res.push(ByteCode::Print);
+                } else {
+                    res.push(ByteCode::PrintLn);
+                }
+                res.push(ByteCode::Push(Value::Nil));  // every expression has a value
+                Ok(res)
+            }
+            Parsed::Token(Token::Inc | Token::Dec | Token::Not | Token::Car | Token::Cdr | Token::IsNil |
+                          Token::StrLen | Token::StrToList | Token::ListToStr, _) => {
+                let mut res = self.generate(&expr[1])?;
+                res.push(match expr[0] {
+                    Parsed::Token(Token::Inc, _) => ByteCode::Inc,
+                    Parsed::Token(Token::Dec, _) => ByteCode::Dec,
+                    Parsed::Token(Token::Car, _) => ByteCode::Car,
+                    Parsed::Token(Token::Cdr, _) => ByteCode::Cdr,
+                    Parsed::Token(Token::IsNil, _) => ByteCode::IsNil,
+                    Parsed::Token(Token::StrLen, _) => ByteCode::Len,
+                    Parsed::Token(Token::StrToList, _) => ByteCode::StrToList,
+                    Parsed::Token(Token::ListToStr, _) => ByteCode::ListToStr,
+                    _ => ByteCode::Not,
+                });
+                Ok(res)
+            }
+            Parsed::Token(Token::Exit, _) => {
+                let mut res = self.generate(&expr[1])?;
+                res.push(ByteCode::Exit);
+                Ok(res)
+            }
+            _ => Err(CodeGenError::IDK(expr[0].span())),
+        }
+    }
+
+    fn double_arg(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        match expr[0] {
+            Parsed::Token(Token::Define, _) => {
+                if let Parsed::Token(Token::Sym(sym), span) = &expr[1] {
+                    if ! self.blocks().scopes.is_empty() {
+                        return self.define_in_block(sym, *span, &expr[2])
                     }
-                    if ! valid {
-                        return Err(CodeGenError::NotValidLambda)
+                    let current_scope = self.sym.last_mut().expect(STACK_LAST_ERROR);
+                    if current_scope.contains_key(sym) {
+                        return Err(CodeGenError::AlreadyDefined(sym.to_string(), *span))
                     }
+                    // bind before generating the value, so a function can call itself
+                    current_scope.insert(sym.to_string(), self.pool_index);
+                    let index = self.alloc_global();
+                    let mut val = match self.generate(&expr[2]) {
+                        Ok(val) => val,
+                        Err(err) => {
+                            // otherwise a failed def leaves a symbol never set
+                            self.sym.last_mut().expect(STACK_LAST_ERROR).remove(sym);
+                            self.pool_index -= 1;
+                            return Err(err)
+                        }
+                    };
+                    val.push(ByteCode::Set(index));
+                    val.push(ByteCode::Get(index));  // should return the value
+                    Ok(val)
+                } else {
+                    Err(CodeGenError::ArgTypeError(expr[1].span()))
                 }
-                let hashmap_iter = argv.iter().enumerate().map(|(index, arg)| {
-                    let sym = if let Parsed::Token(Token::Sym(sym)) = arg { sym } else { todo!() };
-                    (sym.to_string(), format!("@{}", index))  // like elixir &1, but starts from 0
-                });
-                // that♂s good
-                // iterator is elegant like Enum.map/2 in elixir
-                // and use an acc vector will have a compile error
-                // because the compiler don't know the size of vector
-                let replaced = rec_replace_sym(body, &HashMap::from_iter(hashmap_iter));
-                Ok(vec![ByteCode::Push(
-                    Value::Ref(
-                        Rc::new(ObjType::Func(argv.len(), self.generate(&replaced)?))
-                ))])
             }
-            _ => Err(CodeGenError::IDK),
+
+            // changes the binding wherever it is
+            Parsed::Token(Token::Set, _) => {
+                if let Parsed::Token(Token::Sym(sym), span) = &expr[1] {
+                    let (var, cell) = self.resolve(sym, self.funcs.len())
+                        .ok_or_else(|| CodeGenError::SymbolNotFound(sym.to_string(), *span))?;
+                    let mut val = self.generate(&expr[2])?;
+                    match var {
+                        Var::Global(i) => val.extend([ByteCode::Set(i), ByteCode::Get(i)]),
+                        Var::Arg(_) | Var::Local(_) | Var::Capture(_) if cell =>
+                            val.extend([ByteCode::Dup, load_var(&var), ByteCode::CellSet]),
+                        // no closure has it yet, after the value is generated, which may make one
+                        Var::Arg(i) | Var::Local(i) if ! self.blocks().captured.contains(&i) =>
+                            val.extend([ByteCode::StoreLocal(i), ByteCode::LoadLocal(i)]),
+                        _ => return Err(CodeGenError::SetCapture(sym.to_string(), *span)),
+                    }
+                    Ok(val)
+                } else {
+                    Err(CodeGenError::ArgTypeError(expr[1].span()))
+                }
+            }
+
+            Parsed::Token(Token::Cons | Token::StrRef, _) => {
+                let mut res = vec![];
+                append(&mut res, self.generate(&expr[1])?);
+                append(&mut res, self.generate(&expr[2])?);
+                if matches!(expr[0], Parsed::Token(Token::Cons, _)) {
+                    res.push(ByteCode::Cons);
+                } else {
+                    res.push(ByteCode::CharAt);
+                }
+                Ok(res)
+            }
+
+            Parsed::Token(Token::Lambda, _) => {
+                let (function, captures) = self.gen_func(expr, vec![], 0)?;
+                self.close(function, &captures, &[])
+            }
+            _ => Err(CodeGenError::IDK(expr[0].span())),
         }
 
     }
 
-}
+    // the Func of (fn (args) body) and the names it captures.
+    // the functions of a letrec start with their names in `captures`, the first `recs` of them
+    fn gen_func(&mut self, expr: &[Parsed], captures: Captures, recs: usize)
+                -> Result<(Value, Captures), CodeGenError> {
+        let argv = if let Parsed::List(argv, _) = &expr[1] { argv } else { return Err(CodeGenError::NotValidLambda(expr[1].span())) };
+        let body = &expr[2];
+        {
+            // checks if argv are all symbol
+            let mut valid = true;
+            for arg in argv {
+                match arg {
+                    Parsed::Token(Token::Sym(_), _) => (),
+                    _ => valid = false,
+                }
+            }
+            if ! valid {
+                return Err(CodeGenError::NotValidLambda(expr[1].span()))
+            }
+        }
+        let params: HashMap<String, Slot> = argv.iter().enumerate().map(|(index, arg)| {
+            let sym = if let Parsed::Token(Token::Sym(sym), _) = arg { sym } else { todo!() };
+            (sym.to_string(), Slot::local(index, needs_cell(sym, [body])))  // like elixir &1, but starts from 0
+        }).collect();
+        // arguments that are Cells are put in them first
+        let mut code = vec![];
+        for index in (0..argv.len()).filter(|i| params.values().any(|slot| slot.index == *i && slot.cell)) {
+            code.extend([ByteCode::Arg(index), ByteCode::MakeCell, ByteCode::StoreLocal(index)]);
+        }
+        // iterator is elegant like Enum.map/2 in elixir
+        self.funcs.push(FnEnv {
+            params,
+            captures,
+            recs,
+            blocks: Blocks { next: argv.len(), ..Default::default() },
+        });
+        let generated = self.generate_in(body, true);
+        let func = self.funcs.pop().expect(STACK_LAST_ERROR);
+        append(&mut code, generated?);
+        let function = Value::Ref(Rc::new(ObjType::Func(argv.len(), code)));
+        Ok((function, func.captures))
+    }
 
-fn rec_replace_sym(expr: &Parsed, mapper: &HashMap<String, String>) -> Parsed {
-    match expr {
-        Parsed::List(list) => {
-            let mut acc = vec![];
-            for i in list {
-                acc.push(rec_replace_sym(i, mapper))
+    // copy the captured values from where the lambda is created,
+    // the functions of a letrec are captured as they are
+    fn close(&mut self, function: Value, captures: &[(String, bool)], recs: &[Value]) -> Result<Vec<ByteCode>, CodeGenError> {
+        if captures.is_empty() {
+            return Ok(vec![ByteCode::Push(function)])
+        }
+        let mut res = vec![];
+        for (i, (name, _)) in captures.iter().enumerate() {
+            match recs.get(i) {
+                Some(rec) => res.push(ByteCode::Push(rec.clone())),
+                None => res.push(self.load_captured(name)?),
             }
-            Parsed::List(acc)
         }
-        Parsed::Token(Token::Sym(sym)) => {
-            match mapper.get(sym) {
-                Some(to) => Parsed::Token(Token::Sym(to.clone())),
-                None => expr.clone(),
+        res.push(ByteCode::Push(function));
+        res.push(ByteCode::MakeClosure(captures.len()));
+        Ok(res)
+    }
+
+    // the functions bound by a letrec or a def in a block, each of them can call all of them.
+    // they share one list of captures, starting with the functions, so LoadRec can close any of them again
+    fn gen_recs(&mut self, funcs: &[(&str, &Parsed)]) -> Result<(Vec<Value>, Captures), CodeGenError> {
+        let mut captures: Captures = funcs.iter().map(|(name, _)| (name.to_string(), false)).collect();
+        let mut values = vec![];
+        for (_, value) in funcs {
+            let expr = if let Parsed::List(expr, _) = value { expr } else { todo!("{}", SHOULDNOT_REACH) };
+            let (function, shared) = self.gen_func(expr, captures, funcs.len())?;
+            captures = shared;
+            values.push(function);
+        }
+        Ok((values, captures))
+    }
+
+    fn triple_arg(&mut self, list: &Parsed) -> Result<Vec<ByteCode>, CodeGenError> {
+        let expr = if let Parsed::List(expr, _) = list { expr } else { todo!("{}", SHOULDNOT_REACH) };
+        match expr[0] {
+            Parsed::Token(Token::Substring, _) => {
+                let mut res = vec![];
+                for i in &expr[1..] {
+                    append(&mut res, self.generate(i)?);
+                }
+                res.push(ByteCode::Substr);
+                Ok(res)
             }
-        },
-        _ => expr.clone(),
+            _ => Err(CodeGenError::IDK(expr[0].span())),
+        }
     }
+
 }
@@ -0,0 +1,182 @@
+/// macros are lisp functions run on their own VM at compile time,
+/// they take the code as lists and return the code to generate instead
+use crate::frontend::parser::Parsed;
+use crate::frontend::token::{self, Token, Span};
+use crate::frontend::gen::{GenEnv, Checkpoint, CodeGenError};
+use crate::vm::bytecode::ByteCode;
+use crate::vm::value::Value;
+use crate::vm::object::ObjType;
+use crate::vm::machine::VM;
+use crate::builtin::linkedlist::List;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// code as data, symbols and keywords become Sym
+pub fn to_value(data: &Parsed) -> Value {
+    match data {
+        Parsed::Token(token, _) => match token {
+            Token::Nil      => Value::Nil,
+            Token::Bool(b)  => Value::Bool(*b),
+            Token::Int(i)   => Value::Int(*i),
+            Token::Float(f) => Value::Float(*f),
+            Token::Char(c)  => Value::Char(*c),
+            Token::Str(s)   => Value::Ref(Rc::new(ObjType::Str(s.to_string()))),
+            Token::Sym(s)   => Value::Ref(Rc::new(ObjType::Sym(s.to_string()))),
+            keyword => Value::Ref(Rc::new(ObjType::Sym(
+                token::keyword(keyword).unwrap_or_default().to_string()
+            ))),
+        },
+        Parsed::List(list, _) => {
+            let list = list.iter().rev().fold(List::new(), |res, i| res.prepend(to_value(i)));
+            Value::Ref(Rc::new(ObjType::Cons(list)))
+        }
+    }
+}
+
+// data as code, everything gets the span of where it came from.
+// None if there is something code can't have, like a function
+pub fn from_value(value: &Value, span: Span) -> Option<Parsed> {
+    let token = match value {
+        Value::Nil      => Token::Nil,
+        Value::Bool(b)  => Token::Bool(*b),
+        Value::Int(i)   => Token::Int(*i),
+        Value::Float(f) => Token::Float(*f),
+        Value::Char(c)  => Token::Char(*c),
+        Value::Ref(rf) => match &**rf {
+            ObjType::Str(s) => Token::Str(s.to_string()),
+            ObjType::Sym(s) => token::tokenlize(s.to_string()).unwrap_or_else(|_| Token::Sym(s.to_string())),
+            ObjType::Cons(list) => {
+                let list = list.iter().map(|i| from_value(i, span)).collect::<Option<Vec<_>>>()?;
+                return Some(Parsed::List(list, span))
+            }
+            _ => return None,
+        },
+    };
+    Some(Parsed::Token(token, span))
+}
+
+#[derive (Debug, Clone)]
+struct Macro {
+    index: usize,  // the global of the function in the env of the macros
+    argc: usize,   // without the rest
+    rest: bool,    // (a b . rest) gets the other arguments as a list
+}
+
+#[derive (Debug)]
+pub struct Macros {
+    env: GenEnv,
+    vm: VM,
+    defined: HashMap<String, Macro>,
+}
+
+// the macros defined so far, for the REPL to forget a line that failed
+#[derive (Debug)]
+pub struct MacrosCheckpoint {
+    env: Checkpoint,
+    defined: HashMap<String, Macro>,
+}
+
+impl Default for Macros {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Macros {
+    pub fn new() -> Self {
+        Macros {
+            env: GenEnv::new(),
+            vm: VM::default(),
+            defined: HashMap::new(),
+        }
+    }
+
+    pub fn checkpoint(&self) -> MacrosCheckpoint {
+        MacrosCheckpoint { env: self.env.checkpoint(), defined: self.defined.clone() }
+    }
+
+    // the globals of the macros after the checkpoint are set again by the next defmacro
+    pub fn rollback(&mut self, checkpoint: MacrosCheckpoint) {
+        self.env.rollback(checkpoint.env);
+        self.defined = checkpoint.defined;
+    }
+
+    pub fn is_macro(&self, name: &str) -> bool {
+        self.defined.contains_key(name)
+    }
+
+    // (defmacro name (params) body...) is (def name (fn (params) (begin body...))) on the VM of the macros
+    pub fn define(&mut self, expr: &[Parsed], span: Span) -> Result<(), CodeGenError> {
+        if expr.len() < 3 {
+            return Err(CodeGenError::WrongNumberOfArgument(2, (expr.len() - 1) as u8, span))
+        }
+        let name = match &expr[1] {
+            Parsed::Token(Token::Sym(name), _) => name,
+            other => return Err(CodeGenError::ArgTypeError(other.span())),
+        };
+        if self.is_macro(name) {
+            return Err(CodeGenError::MacroAlreadyDefined(name.to_string(), expr[1].span()))
+        }
+        let mut params = match &expr[2] {
+            Parsed::List(params, _) => params.clone(),
+            other => return Err(CodeGenError::NotValidLambda(other.span())),
+        };
+        let dot = params.iter().position(|p| matches!(p, Parsed::Token(Token::Sym(s), _) if s == "."));
+        let rest = match dot {
+            Some(dot) if dot + 2 == params.len() => {
+                params.remove(dot);
+                true
+            }
+            Some(dot) => return Err(CodeGenError::NotValidLambda(params[dot].span())),
+            None => false,
+        };
+
+        let mut body = vec![Parsed::Token(Token::Begin, span)];
+        body.extend(expr[3..].iter().cloned());
+        let argc = params.len() - usize::from(rest);
+        let def = Parsed::List(vec![
+            Parsed::Token(Token::Define, span),
+            expr[1].clone(),
+            Parsed::List(vec![
+                Parsed::Token(Token::Lambda, span),
+                Parsed::List(params, expr[2].span()),
+                Parsed::List(body, span),
+            ], span),
+        ], span);
+        let code = self.env.generate_with_halt(&def)?;
+        self.run(&code, span)?;
+        let index = self.env.sym[0][name];
+        self.defined.insert(name.to_string(), Macro { index, argc, rest });
+        Ok(())
+    }
+
+    // the code the macro returns for the arguments
+    pub fn expand(&mut self, name: &str, args: &[Parsed], span: Span) -> Result<Parsed, CodeGenError> {
+        let mac = &self.defined[name];
+        if args.len() < mac.argc || (! mac.rest && args.len() > mac.argc) {
+            return Err(CodeGenError::WrongNumberOfArgument(mac.argc as u8, args.len() as u8, span))
+        }
+        let mut code: Vec<ByteCode> = args[..mac.argc].iter()
+            .map(|arg| ByteCode::Push(to_value(arg)))
+            .collect();
+        if mac.rest {
+            code.push(ByteCode::Push(to_value(&Parsed::List(args[mac.argc..].to_vec(), span))));
+        }
+        code.extend([ByteCode::Get(mac.index), ByteCode::CallTopFn(mac.argc + usize::from(mac.rest)), ByteCode::HALT]);
+
+        let expanded = self.run(&code, span)?;
+        from_value(&expanded, span)
+            .ok_or_else(|| CodeGenError::MacroError(format!("`{}` returned {:?}, not code", name, expanded), span))
+    }
+
+    fn run(&mut self, code: &[ByteCode], span: Span) -> Result<Value, CodeGenError> {
+        let result = self.vm.run(code);
+        self.vm.reset_pc();
+        let value = self.vm.stack.pop().unwrap_or(Value::Nil);
+        self.vm.stack.clear();
+        match result {
+            Ok(()) => Ok(value),
+            Err(err) => Err(CodeGenError::MacroError(err.kind.to_string(), span)),
+        }
+    }
+}
@@ -2,3 +2,5 @@ pub mod token;
 pub mod parser;
 pub mod gen;
 pub mod repl;
+pub mod diagnostic;
+pub mod macros;
@@ -1,78 +1,209 @@
 use regex::{Regex, Captures};
-use crate::frontend::token::{self, Token};
+use crate::frontend::token::{self, Token, Span};
+use crate::literal::LiteralError;
+use std::fmt::{Display, Formatter};
 
 #[derive (Clone, PartialEq, Debug)]
 pub enum Parsed {
-    Token(Token),
-    List(Vec<Parsed>),
+    Token(Token, Span),
+    List(Vec<Parsed>, Span),
 }
 
-#[derive (Debug)]
+impl Parsed {
+    pub fn span(&self) -> Span {
+        match self {
+            Parsed::Token(_, span) | Parsed::List(_, span) => *span,
+        }
+    }
+}
+
+#[derive (Debug, PartialEq)]
 pub enum ParseError {
-    MisMatchedBracket,  // or MisMatchedParentheses
-    InvalidCharacter,
+    MisMatchedBracket(Span),  // or MisMatchedParentheses
+    InvalidCharacter(Span),
+    InvalidLiteral(LiteralError, Span),  // a string or a char
+    Empty,
+    InForm(usize, Box<ParseError>),  // index of the top-level form that failed
+}
+
+impl ParseError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ParseError::MisMatchedBracket(span)
+                | ParseError::InvalidCharacter(span)
+                | ParseError::InvalidLiteral(_, span) => Some(*span),
+            ParseError::Empty => None,
+            ParseError::InForm(_, err) => err.span(),
+        }
+    }
 }
 
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::MisMatchedBracket(_) => write!(f, "mismatched bracket"),
+            ParseError::InvalidCharacter(_) => write!(f, "invalid character"),
+            ParseError::InvalidLiteral(err, _) => write!(f, "{}", err),
+            ParseError::Empty => write!(f, "nothing to parse"),
+            ParseError::InForm(n, err) => write!(f, "{} in form {}", err, n),
+        }
+    }
+}
+
+
+// only the first form
+pub fn parse(input: &str) -> Result<Parsed, ParseError> {
+    match parse_program(input)?.into_iter().next() {
+        Some(parsed) => Ok(parsed),
+        None => Err(ParseError::Empty),
+    }
+}
 
-pub fn parse(input: &String) -> Result<Parsed, ParseError> {
+// every top-level form, comments start with ;
+pub fn parse_program(input: &str) -> Result<Vec<Parsed>, ParseError> {
     // from github.com/kanaka/mal/blob/master/process/guide.md#step-2-eval
+    // \u{41} is a char, though braces aren't allowed in other atoms
     let re_parse =
-        Regex::new(r#"[\s,]*([\[\]{}()']|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]*)"#).unwrap();
+        Regex::new(r#"\s*(,@|[\[\]{}()'`,]|"(?:\\.|[^\\"])*"?|;.*|\\u\{[^\s{}()]*\}|[^\s\[\]{}('"`,;)]*)"#).unwrap();
     let caps: Vec<Captures> = re_parse.captures_iter(input).collect();
     //println!("{:?}", &caps);
-    let mut brackets = (0, 0);
-    for c in &caps {
-        match &c[1] {
-            "(" => brackets.0 += 1,
-            ")" => brackets.1 += 1,
-            _ => (),
-        }
-    }
-    if brackets.0 != brackets.1 {
-        return Err(ParseError::MisMatchedBracket)
-    }
+
+    // byte offset of each line, for the spans
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let span_of = |start: usize, s: &str| {
+        let line = line_starts.partition_point(|&line_start| line_start <= start);
+        let col = input[line_starts[line - 1]..start].chars().count() + 1;
+        Span { line, col, len: s.chars().count() }
+    };
 
     // zhihu: zhuanlan.zhihu.com/p/260157026
     // 一个 List 没完成时，又有新的 List 要开始，旧的 List 用一个栈保存起来
-    let mut stack = vec![];
+    // 'x is read as (quote x), a frame of the stack that is closed by the form after it.
+    // so are `x (quasiquote x), ,x (unquote x) and ,@x (unquote-splicing x)
+    let mut stack: Vec<(Vec<Parsed>, Span, bool)> = vec![];
     let mut list  = vec![];
+    let mut list_span = Span::default();
+    let mut quoting = false;
+    let close_quotes = |stack: &mut Vec<(Vec<Parsed>, Span, bool)>, list: &mut Vec<Parsed>,
+                        list_span: &mut Span, quoting: &mut bool| {
+        while *quoting && list.len() == 2 {
+            let (mut nlist, nspan, nquoting) = stack.pop().expect("quote frame");
+            nlist.push(Parsed::List(std::mem::take(list), *list_span));
+            *list = nlist;
+            *list_span = nspan;
+            *quoting = nquoting;
+        }
+    };
     for i in &caps {
         // Captures[1] is the capture group
-        match &i[1] {
+        let group = i.get(1).unwrap();
+        let span = span_of(group.start(), group.as_str());
+        match group.as_str() {
             "(" => {
-                stack.push(list);
+                stack.push((list, list_span, quoting));
                 list = vec![];
+                list_span = span;
+                quoting = false;
+            }
+            "'" | "`" | "," | ",@" => {
+                let quote = match group.as_str() {
+                    "'" => Token::Quote,
+                    "`" => Token::Quasiquote,
+                    "," => Token::Unquote,
+                    _   => Token::UnquoteSplicing,
+                };
+                stack.push((list, list_span, quoting));
+                list = vec![Parsed::Token(quote, span)];
+                list_span = span;
+                quoting = true;
             }
             ")" => {
-                let mut nlist =  // 将上一个 list 出栈
+                if quoting {
+                    // nothing to quote
+                    return Err(ParseError::InForm(stack.first().map_or(list.len(), |top| top.0.len()),
+                                                  Box::new(ParseError::InvalidCharacter(list_span))))
+                }
+                let (mut nlist, nspan, nquoting) =  // 将上一个 list 出栈
                     match stack.pop() {
                         Some(nl) => nl,
-                        None => return Err(ParseError::MisMatchedBracket),
+                        // the forms before it are complete
+                        None => return Err(ParseError::InForm(list.len(), Box::new(ParseError::MisMatchedBracket(span)))),
                     };
-                nlist.push(Parsed::List(list));        // 当前的 list 作为值存入
+                if span.line == list_span.line {
+                    list_span.len = span.col - list_span.col + 1;
+                }
+                nlist.push(Parsed::List(list, list_span));  // 当前的 list 作为值存入
                 list = nlist;
+                list_span = nspan;
+                quoting = nquoting;
+                close_quotes(&mut stack, &mut list, &mut list_span, &mut quoting);
+            }
+            "" => (),  // trailing whitespace
+            s if s.starts_with(';') => (),
+            "[" | "]" | "{" | "}" => {
+                return Err(ParseError::InForm(stack.first().map_or(list.len(), |top| top.0.len()),
+                                              Box::new(ParseError::InvalidCharacter(span))))
+            }
+            s if s.starts_with('"') && (s.len() == 1 || ! s.ends_with('"')) => {
+                // unterminated string
+                return Err(ParseError::InForm(stack.first().map_or(list.len(), |top| top.0.len()),
+                                              Box::new(ParseError::InvalidCharacter(span))))
+            }
+            s => {
+                let token = match token::tokenlize(s.to_string()) {
+                    Ok(token) => token,
+                    Err(err) => return Err(ParseError::InForm(stack.first().map_or(list.len(), |top| top.0.len()),
+                                                              Box::new(ParseError::InvalidLiteral(err, span)))),
+                };
+                list.push(Parsed::Token(token, span));
+                close_quotes(&mut stack, &mut list, &mut list_span, &mut quoting);
             }
-            s => list.push(Parsed::Token(
-                token::tokenlize(s.to_string())
-            )),
         }
     };
 
-    Ok(list[0].clone())
+    if quoting {
+        return Err(ParseError::InForm(stack.first().map_or(list.len(), |top| top.0.len()),
+                                      Box::new(ParseError::InvalidCharacter(list_span))))
+    }
+    // the outermost unclosed list is the one after the complete forms
+    if let Some((top_level, _, _)) = stack.first() {
+        let open_span = stack.get(1).map_or(list_span, |second| second.1);
+        return Err(ParseError::InForm(top_level.len(), Box::new(ParseError::MisMatchedBracket(open_span))))
+    }
+    Ok(list)
 }
 
 
 #[cfg (test)]
 mod tests {
     use super::*;
-    use super::Parsed::{Token, List};
     use crate::frontend::token::Token::*;
 
+    // the expected forms have no spans, what is parsed goes through shape first
+    #[allow(non_snake_case)]
+    fn Token(token: token::Token) -> Parsed {
+        Parsed::Token(token, Span::default())
+    }
+
+    #[allow(non_snake_case)]
+    fn List(list: Vec<Parsed>) -> Parsed {
+        Parsed::List(list, Span::default())
+    }
+
+    fn shape(parsed: Parsed) -> Parsed {
+        match parsed {
+            Parsed::Token(token, _) => Token(token),
+            Parsed::List(list, _) => List(list.into_iter().map(shape).collect()),
+        }
+    }
+
 
     #[test]
     fn test_parse_str() {
         let input = "123".to_string();
-        assert_eq!(Token(Int(123)), parse(&input).unwrap());
+        assert_eq!(Token(Int(123)), shape(parse(&input).unwrap()));
     }
 
     #[test]
@@ -84,7 +215,85 @@ mod tests {
                      vec![Token(Add), Token(Int(2)), Token(Int(3))]
                  )
             ]
-        ), parse(&input).unwrap());
+        ), shape(parse(&input).unwrap()));
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let input = r#"
+; square
+(def sq (fn (x) (* x x)))  ; trailing comment
+(sq 2)
+"#;
+        assert_eq!(vec![
+            List(vec![Token(Define), Token(Sym("sq".to_string())),
+                      List(vec![Token(Lambda), List(vec![Token(Sym("x".to_string()))]),
+                                List(vec![Token(Mul), Token(Sym("x".to_string())), Token(Sym("x".to_string()))])])]),
+            List(vec![Token(Sym("sq".to_string())), Token(Int(2))]),
+        ], parse_program(input).unwrap().into_iter().map(shape).collect::<Vec<_>>());
+
+        assert_eq!(Ok(vec![]), parse_program("  ; nothing\n"));
+        assert_eq!(Err(ParseError::Empty), parse(""));
+    }
+
+    #[test]
+    fn test_parse_error_form() {
+        let err = parse_program("(+ 1 2) (+ 1 (+ 2 3) (+ 4 5)").unwrap_err();
+        assert!(matches!(&err, ParseError::InForm(1, e) if matches!(**e, ParseError::MisMatchedBracket(_))));
+        assert_eq!(Some((1, 9)), err.span().map(|span| (span.line, span.col)));
+
+        let err = parse_program("(+ 1 2) 3 4)").unwrap_err();
+        assert!(matches!(&err, ParseError::InForm(3, e) if matches!(**e, ParseError::MisMatchedBracket(_))));
+        assert_eq!(Some((1, 12)), err.span().map(|span| (span.line, span.col)));
+
+        let err = parse_program("(+ 1 99999999999999999999)").unwrap_err();
+        assert!(matches!(&err, ParseError::InForm(0, e)
+                         if matches!(**e, ParseError::InvalidLiteral(LiteralError::IntOutOfRange(_), _))));
+        assert_eq!(Some((1, 6)), err.span().map(|span| (span.line, span.col)));
+
+        let err = parse_program("(\n  (str \"abc))").unwrap_err();
+        assert!(matches!(&err, ParseError::InForm(0, e) if matches!(**e, ParseError::InvalidCharacter(_))));
+        assert_eq!(2, err.span().unwrap().line);
+    }
+
+    #[test]
+    fn test_span() {
+        let input = "(def x 1)\n(+ x\n   λ)";
+        let forms = parse_program(input).unwrap();
+        let span = |parsed: &Parsed| {
+            let span = parsed.span();
+            (span.line, span.col, span.len)
+        };
+        assert_eq!((1, 1, 9), span(&forms[0]));
+        if let Parsed::List(list, _) = &forms[1] {
+            assert_eq!((2, 4, 1), span(&list[1]));
+            assert_eq!((3, 4, 1), span(&list[2]));  // columns count chars
+        }
+        assert_eq!((2, 1, 1), span(&forms[1]));  // spans lines
+        assert_ne!(parse("(+ 1 2)"), parse(" (+ 1 2)"));
+        assert_eq!(shape(parse("(+ 1 2)").unwrap()), shape(parse(" (+ 1 2)").unwrap()));
+    }
+
+    #[test]
+    fn test_quote() {
+        let quote = |parsed| List(vec![Token(Quote), parsed]);
+        assert_eq!(quote(Token(Sym("x".to_string()))), shape(parse("'x").unwrap()));
+        assert_eq!(List(vec![Token(Car), quote(List(vec![Token(Int(1)), quote(quote(Token(Int(2))))]))]),
+                   shape(parse("(car '(1 ''2))").unwrap()));
+        assert_eq!(2, parse_program("'(1) 'a").unwrap().len());
+
+        assert!(matches!(parse_program("(car ')"), Err(ParseError::InForm(0, _))));
+        assert!(matches!(parse_program("1 '"), Err(ParseError::InForm(1, _))));
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        let wrap = |token, parsed| List(vec![Token(token), parsed]);
+        let x = || Token(Sym("x".to_string()));
+        assert_eq!(wrap(Quasiquote, List(vec![wrap(Unquote, x()), wrap(UnquoteSplicing, x())])),
+                   shape(parse("`(,x ,@x)").unwrap()));
+        // comma is not whitespace any more
+        assert_eq!(List(vec![x(), wrap(Unquote, x())]), shape(parse("(x ,x)").unwrap()));
     }
 
     #[test]
@@ -1,14 +1,59 @@
 use dialoguer::{Input, History};
 use crate::frontend::parser;
+use crate::frontend::diagnostic;
 use crate::frontend::gen::GenEnv;
 use crate::vm::machine::VM;
+use crate::vm::value::Value;
 use std::collections::VecDeque;
 
+// the lines typed so far, a line that fails leaves nothing behind
+pub struct Session {
+    env: GenEnv,
+    pub vm: VM,
+}
+
+impl Session {
+    pub fn new(vm: VM) -> Self {
+        Session { env: GenEnv::new(), vm }
+    }
+
+    // the value of the last form, None for a line without any or an empty stack, or the error to show
+    pub fn eval(&mut self, input: &str) -> Result<Option<Value>, String> {
+        let forms = parser::parse_program(input)
+            .map_err(|err| diagnostic::render(input, "repl", "PARSER", &err.to_string(), err.span()))?;
+        if forms.is_empty() {
+            return Ok(None)
+        }
+        let checkpoint = self.env.checkpoint();
+        // pasted snippets may have several forms, the last value is shown
+        let code = match self.env.generate_program(&forms) {
+            Ok(code) => code,
+            Err(err) => {
+                // the forms before the one that failed are forgotten too
+                self.env.rollback(checkpoint);
+                return Err(diagnostic::render(input, "repl", "CODEGEN", &err.to_string(), Some(err.span())))
+            }
+        };
+        if self.vm.is_debug() {
+            println!("{:?}\n{:?}", &code, &self.env);
+        }
+        let result = self.vm.run(&code);
+        self.vm.reset_pc();  // otherwise it just halts
+        match result {
+            Ok(()) => Ok(self.vm.stack.last().cloned()),
+            Err(err) => {
+                // the defs of the line never got their values
+                self.env.rollback(checkpoint);
+                Err(err.to_string())
+            }
+        }
+    }
+}
+
 pub fn repl() {
     println!("pid: {}", std::process::id());
 
-    let mut env = GenEnv::new();
-    let mut vm = VM::new(100, true);
+    let mut session = Session::new(VM::new(100, true));
 
     let mut counter = 0;
     let mut history = ReplHistory::new();
@@ -19,20 +64,17 @@ pub fn repl() {
             .interact_text()
             .unwrap();
 
-        match parser::parse(&input) {
-            Ok(parsed) => {
-                match env.generate_with_halt(&parsed) {
-                    Ok(code) => {
-                        println!("{:?}\n{:?}", &code, &env);
-                        vm.run(&code);
-                        println!("{:?}", vm.stack.last().unwrap());
-                        vm.reset_pc();  // otherwise it just halts
-                        counter += 1;
-                    }
-                    Err(err) => println!("[CODEGEN]: {:?}", err)
+        match session.eval(&input) {
+            Ok(value) => {
+                if let Some(code) = session.vm.exit_code {
+                    std::process::exit(code as i32)
+                }
+                if let Some(val) = value {
+                    println!("{:?}", val);
+                    counter += 1;
                 }
             }
-            Err(err) => println!("[PARSER]: {:?}", err),
+            Err(message) => println!("{}", message),
         }
     }
 }
@@ -44,10 +86,7 @@ struct ReplHistory {
 // new feature in 0.10.1
 impl History<String> for ReplHistory {
     fn read(&self, pos: usize) -> Option<String> {
-        match self.history.get(pos) {
-            Some(s) => Some(s.clone()),
-            None => None,
-        }
+        self.history.get(pos).cloned()
     }
 
     fn write(&mut self, val: &String) {
@@ -1,5 +1,14 @@
 use std::collections::HashMap;
 use regex::Regex;
+use crate::literal::{self, LiteralError};
+
+// where a token is in the source, line and col start from 1
+#[derive (Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
 
 #[derive (Clone, PartialEq, Debug)]
 pub enum Token {
@@ -26,13 +35,42 @@ pub enum Token {
 
     Define,
     Set,
+    If,
     Cond,
+    Else,
+    When,
+    Unless,
     Match,
 
+    Let,
+    LetStar,
+    LetRec,
+    Begin,
+
     List,
     Car,
     Cdr,
+    Cons,
+    IsNil,
+
+    MakeStr,
+    StrAppend,
+    StrLen,
+    StrRef,
+    Substring,
+    StrToList,
+    ListToStr,
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    DefMacro,
+
+    Display,
+    DisplayLn,
+    Newline,
+    ReadLine,
+    Exit,
 
     Lambda,
     Require,
@@ -49,77 +87,116 @@ pub enum Token {
 
 use Token::*;
 
-pub fn tokenlize(s: String) -> Token {
-    let token_map: HashMap<&'static str, Token> = HashMap::from([
-        ("+"       , Add),
-        ("-"       , Sub),
-        ("*"       , Mul),
-        ("/"       , Div),
-        ("%"       , Rem),
-        ("++"      , Inc),
-        ("--"      , Dec),
-        ("&"       , And),
-        ("|"       , Or),
-        ("!"       , Not),
-        ("^"       , Xor),
-        (">"       , Greater),
-        (">="      , GreaterEq),
-        ("<"       , Less),
-        ("<="      , LessEq),
-        ("=="      , Eq),
-        ("!="      , Neq),
-        ("==="     , Seq),
-        ("!=="     , Sneq),
-
-        ("def"     , Define),
-        ("set"     , Set),
-        ("cond"    , Cond),
-        ("match"   , Match),
-
-        ("list"    , List),
-        ("car"     , Car),
-        ("cdr"     , Cdr),
-
-        ("fn"      , Lambda),
-        ("λ"       , Lambda),
-        ("require" , Require),
-        ("provide" , Provide),
-
-        ("nil"     , Nil),
-        ("true"    , Bool(true)),
-        ("false"   , Bool(false))
-    ]);
+// the keywords and their tokens, the first name of a token is how it is shown
+const KEYWORDS: &[(&str, Token)] = &[
+    ("+"       , Add),
+    ("-"       , Sub),
+    ("*"       , Mul),
+    ("/"       , Div),
+    ("%"       , Rem),
+    ("++"      , Inc),
+    ("--"      , Dec),
+    ("&"       , And),
+    ("|"       , Or),
+    ("!"       , Not),
+    ("^"       , Xor),
+    (">"       , Greater),
+    (">="      , GreaterEq),
+    ("<"       , Less),
+    ("<="      , LessEq),
+    ("=="      , Eq),
+    ("!="      , Neq),
+    ("==="     , Seq),
+    ("!=="     , Sneq),
+
+    ("def"     , Define),
+    ("set"     , Set),
+    ("if"      , If),
+    ("cond"    , Cond),
+    ("else"    , Else),
+    ("when"    , When),
+    ("unless"  , Unless),
+    ("match"   , Match),
+
+    ("let"     , Let),
+    ("let*"    , LetStar),
+    ("letrec"  , LetRec),
+    ("begin"   , Begin),
+    ("do"      , Begin),
+
+    ("list"    , List),
+    ("car"     , Car),
+    ("cdr"     , Cdr),
+    ("cons"    , Cons),
+    ("null?"   , IsNil),
+    ("quote"   , Quote),
+    ("quasiquote"       , Quasiquote),
+    ("unquote"          , Unquote),
+    ("unquote-splicing" , UnquoteSplicing),
+    ("defmacro"         , DefMacro),
+
+    ("string"        , MakeStr),
+    ("string-append" , StrAppend),
+    ("string-length" , StrLen),
+    ("string-ref"    , StrRef),
+    ("substring"     , Substring),
+    ("string->list"  , StrToList),
+    ("list->string"  , ListToStr),
+
+    ("display"   , Display),
+    ("displayln" , DisplayLn),
+    ("newline"   , Newline),
+    ("read-line" , ReadLine),
+    ("exit"      , Exit),
+
+    ("fn"      , Lambda),
+    ("λ"       , Lambda),
+    ("require" , Require),
+    ("provide" , Provide),
+
+    ("nil"     , Nil),
+    ("true"    , Bool(true)),
+    ("false"   , Bool(false))
+];
+
+// the name of a keyword token
+pub fn keyword(token: &Token) -> Option<&'static str> {
+    KEYWORDS.iter().find(|(_, t)| t == token).map(|(name, _)| *name)
+}
+
+// strings and chars can have escapes that are wrong
+pub fn tokenlize(s: String) -> Result<Token, LiteralError> {
+    let token_map: HashMap<&'static str, Token> = HashMap::from_iter(KEYWORDS.iter().cloned());
 
     let re_int   = Regex::new(r#"^(\d+)$"#)    .unwrap();
     let re_float = Regex::new(r#"^(\d+.\d+)$"#).unwrap();
     let re_str   = Regex::new(r#"^"(.*)"$"#)   .unwrap();
-    let re_char  = Regex::new(r#"^\\(.)$"#)    .unwrap();
-    // char is something like \A
+    let re_char  = Regex::new(r#"^\\(.+)$"#)    .unwrap();
+    // char is something like \A, \space or \u{41}
     // simmilar with clojure and racket
     // because ' is quote
 
-    match token_map.get(s.as_str()) {
+    let token = match token_map.get(s.as_str()) {
         Some(token) => token.clone(),
         None => {
             if re_int.is_match(&s) {
                 let cap = re_int.captures(&s).unwrap();
-                let the_int = cap[1].parse::<i64>().unwrap();
+                let the_int = literal::int(&cap[1])?;
                 Int(the_int)
             } else if re_float.is_match(&s) {
                 let cap = re_float.captures(&s).unwrap();
                 let the_float = cap[1].parse::<f64>().unwrap();
                 Float(the_float)
             } else if re_char.is_match(&s) {
-                // copied from asm.rs
                 let cap = re_char.captures(&s).unwrap();
-                let the_char = cap[1].chars().collect::<Vec<_>>()[0];
-                Char(the_char as u32)
+                Char(literal::lisp_char(&cap[1])? as u32)
             } else if re_str.is_match(&s) {
                 let cap = re_str.captures(&s).unwrap();
-                Str(cap[1].to_string())
+                Str(literal::unescape(&cap[1])?)
             } else {
                 Sym(s)
             }
         }
-    }
+    };
+    Ok(token)
 }
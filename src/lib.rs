@@ -1,4 +1,6 @@
 pub mod vm;
 pub mod asm;
+pub mod disasm;
+pub mod literal;
 pub mod builtin;
 pub mod frontend;
@@ -0,0 +1,172 @@
+use std::fmt::{self, Display, Formatter};
+
+// strings and chars of both the asm and the lisp,
+// escapes are \n \t \r \0 \\ \" \' and \u{hex}
+
+#[derive (Debug, PartialEq)]
+pub enum LiteralError {
+    UnknownEscape(char),
+    InvalidUnicode(String),  // what is between the braces
+    TrailingBackslash,
+    NotAChar(String),
+    IntOutOfRange(String),
+}
+
+impl Display for LiteralError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LiteralError::UnknownEscape(c) => write!(f, "unknown escape `\\{}`", c),
+            LiteralError::InvalidUnicode(hex) => write!(f, "invalid unicode escape `\\u{{{}}}`", hex),
+            LiteralError::TrailingBackslash => write!(f, "nothing to escape after `\\`"),
+            LiteralError::NotAChar(s) => write!(f, "`{}` is not a single char", s),
+            LiteralError::IntOutOfRange(s) => write!(f, "`{}` does not fit in an int", s),
+        }
+    }
+}
+
+// for chars in lisp, \space is ' '
+const NAMED_CHARS: &[(&str, char)] = &[
+    ("space"    , ' '),
+    ("newline"  , '\n'),
+    ("tab"      , '\t'),
+    ("return"   , '\r'),
+    ("nul"      , '\0'),
+    ("backspace", '\u{8}'),
+    ("escape"   , '\u{1b}'),
+    ("delete"   , '\u{7f}'),
+];
+
+pub fn named_char(name: &str) -> Option<char> {
+    NAMED_CHARS.iter().find(|(n, _)| *n == name).map(|(_, c)| *c)
+}
+
+pub fn char_name(c: char) -> Option<&'static str> {
+    NAMED_CHARS.iter().find(|(_, n)| *n == c).map(|(name, _)| *name)
+}
+
+// the digits of an int literal, which may still be too big for an i64
+pub fn int(s: &str) -> Result<i64, LiteralError> {
+    s.parse::<i64>().map_err(|_| LiteralError::IntOutOfRange(s.to_string()))
+}
+
+fn unicode(hex: &str) -> Result<char, LiteralError> {
+    let invalid = || LiteralError::InvalidUnicode(hex.to_string());
+    if hex.is_empty() || hex.len() > 6 {
+        return Err(invalid())
+    }
+    let code = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+    char::from_u32(code).ok_or_else(invalid)
+}
+
+pub fn unescape(s: &str) -> Result<String, LiteralError> {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue
+        }
+        let escaped = match chars.next().ok_or(LiteralError::TrailingBackslash)? {
+            'n'  => '\n',
+            't'  => '\t',
+            'r'  => '\r',
+            '0'  => '\0',
+            '\\' => '\\',
+            '"'  => '"',
+            '\'' => '\'',
+            'u' => {
+                let rest = chars.as_str();
+                let hex = rest.strip_prefix('{')
+                    .and_then(|rest| rest.find('}').map(|end| &rest[..end]))
+                    .ok_or(LiteralError::UnknownEscape('u'))?;
+                let c = unicode(hex)?;
+                chars = rest[hex.len() + 2..].chars();
+                c
+            }
+            other => return Err(LiteralError::UnknownEscape(other)),
+        };
+        res.push(escaped);
+    }
+    Ok(res)
+}
+
+// a char written the way it is in a string, e.g. `\n` or `a`
+pub fn unescape_char(s: &str) -> Result<char, LiteralError> {
+    let res = unescape(s)?;
+    let mut chars = res.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(LiteralError::NotAChar(s.to_string())),
+    }
+}
+
+// the lisp char after the `\`: a char, a name, or u{hex}
+pub fn lisp_char(s: &str) -> Result<char, LiteralError> {
+    let mut chars = s.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(c)
+    }
+    if let Some(c) = named_char(s) {
+        return Ok(c)
+    }
+    match s.strip_prefix("u{").and_then(|hex| hex.strip_suffix('}')) {
+        Some(hex) => unicode(hex),
+        None => Err(LiteralError::NotAChar(s.to_string())),
+    }
+}
+
+// what unescape turns back into s
+pub fn escape(s: &str) -> String {
+    s.chars().map(escape_char).collect()
+}
+
+pub fn escape_char(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\0' => "\\0".to_string(),
+        '\\' => "\\\\".to_string(),
+        '"'  => "\\\"".to_string(),
+        c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+
+#[cfg (test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(Ok("a\n\t\\\"'\0b".to_string()), unescape(r#"a\n\t\\\"\'\0b"#));
+        assert_eq!(Ok("λ=\u{1F980}!".to_string()), unescape(r"\u{3bb}=\u{1f980}!"));
+        assert_eq!(Err(LiteralError::UnknownEscape('q')), unescape(r"\q"));
+        assert_eq!(Err(LiteralError::UnknownEscape('u')), unescape(r"\u41"));
+        assert_eq!(Err(LiteralError::InvalidUnicode("d800".to_string())), unescape(r"\u{d800}"));
+        assert_eq!(Err(LiteralError::TrailingBackslash), unescape("a\\"));
+
+        let s = "tab\there \"quoted\" back\\slash \u{7} λ";
+        assert_eq!(Ok(s.to_string()), unescape(&escape(s)));
+    }
+
+    #[test]
+    fn test_int() {
+        assert_eq!(Ok(-42), int("-42"));
+        assert_eq!(Ok(i64::MAX), int("9223372036854775807"));
+        assert_eq!(Err(LiteralError::IntOutOfRange("99999999999999999999".to_string())), int("99999999999999999999"));
+    }
+
+    #[test]
+    fn test_chars() {
+        assert_eq!(Ok('\n'), unescape_char(r"\n"));
+        assert!(unescape_char("ab").is_err());
+        assert_eq!(Ok(' '), lisp_char("space"));
+        assert_eq!(Ok('\n'), lisp_char("newline"));
+        assert_eq!(Ok('u'), lisp_char("u"));
+        assert_eq!(Ok('A'), lisp_char("u{41}"));
+        assert_eq!(Err(LiteralError::NotAChar("spaces".to_string())), lisp_char("spaces"));
+        assert_eq!(Some("space"), char_name(' '));
+    }
+}
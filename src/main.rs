@@ -4,13 +4,18 @@ use axolotl::vm::machine::VM;
 use axolotl::vm::bytecode::ByteCode;
 use axolotl::vm::value::Value;
 use axolotl::asm;
+use axolotl::disasm;
 use axolotl::frontend::repl;
+use axolotl::frontend::parser;
+use axolotl::frontend::diagnostic;
+use axolotl::frontend::gen::GenEnv;
 
 use std::fs::{self, OpenOptions, File};
+use std::path::Path;
 use std::time::Instant;
 
 use clap::{Arg, App, SubCommand};
-use bincode;
+use bincode::config::Config;
 
 fn prog(delay: u64, render: bool, debug: bool) {
     let program: Vec<ByteCode> = vec![
@@ -22,10 +27,24 @@ fn prog(delay: u64, render: bool, debug: bool) {
     ];
 
     let mut machine = VM::new(delay, render).set_debug(debug);
-    machine.run(&program);
+    if let Err(err) = machine.run(&program) {
+        eprintln!("\n{}", err);
+    }
     println!("\n{:?}", machine);
 }
 
+fn write_bin(program: Vec<ByteCode>, output_filename: &str, config: impl Config) {
+    let mut bin_file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(output_filename)
+        .unwrap();
+
+    bincode::encode_into_std_write(program, &mut bin_file, config).unwrap();
+    println!("bytecode: {}", output_filename);
+}
+
 fn main() {
     // https://www.jianshu.com/p/bc693e49670f
     let matches = App::new("AxolotlVM")
@@ -63,6 +82,12 @@ fn main() {
                          .help("assembly file")
                          .required(true)))
 
+        .subcommand(App::new("dis")
+                    .about("Disassemble the binary to asm.")
+                    .arg(Arg::new("BIN")
+                         .help("bytecode file")
+                         .required(true)))
+
         .subcommand(App::new("com")
                     .about("Compile the source file to binary.")
                     .arg(Arg::new("SOURCE")
@@ -80,11 +105,10 @@ fn main() {
 
     let config = bincode::config::standard()
         .with_little_endian()
-        .with_variable_int_encoding()
-        .skip_fixed_array_length();
+        .with_variable_int_encoding();
 
     if status {
-        matches.subcommand_matches("run").map(|m| {
+        if let Some(m) = matches.subcommand_matches("run") {
             status = false;
 
             let delay = m.value_of("delay")
@@ -102,55 +126,99 @@ fn main() {
                 println!("axolotl bin: {}", file);
 
                 let mut bin_file = File::open(file).unwrap();
-                let program = bincode::decode_from_std_read(&mut bin_file, config).unwrap();
+                let program: Vec<ByteCode> = bincode::decode_from_std_read(&mut bin_file, config).unwrap();
 
                 let now = Instant::now();
                 let mut machine = VM::new(delay, render).set_debug(debug);
-                machine.run(&program);
+                let result = machine.run(&program);
 
                 let elapsed = now.elapsed();
                 println!("elapsed: {:?}", elapsed);
                 println!("{:?}", machine);
+                if let Err(err) = result {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+                if let Some(code) = machine.exit_code {
+                    std::process::exit(code as i32);
+                }
             } else {
                 prog(delay, render, debug)
             }
-        });
+        }
     }
 
     if status {
-        matches.subcommand_matches("asm").map(|m| {
+        if let Some(m) = matches.subcommand_matches("asm") {
             status = false;
             let file = m.value_of("ASM").unwrap();
             println!("asm: {}", file);
 
             let content = fs::read_to_string(file).unwrap();
-            let program = asm::compile_to_enum(content);
+            let program = match asm::compile_file(content.clone(), Path::new(file)) {
+                Ok(program) => program,
+                Err(errors) => {
+                    for err in errors {
+                        eprintln!("{}\n", diagnostic::render(&content, file, "ASM", &err.to_string(), Some(err.span())));
+                    }
+                    std::process::exit(1);
+                }
+            };
 
             let output_filename = file.replace(".asm", ".abin");
-            let mut bin_file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&output_filename)
-                .unwrap();
+            write_bin(program, &output_filename, config);
+        }
+    }
 
-            bincode::encode_into_std_write(program, &mut bin_file, config).unwrap();
-            println!("bytecode: {}", &output_filename);
-        });
+    if status {
+        if let Some(m) = matches.subcommand_matches("dis") {
+            status = false;
+            let file = m.value_of("BIN").unwrap();
+
+            let mut bin_file = File::open(file).unwrap();
+            let program: Vec<ByteCode> = bincode::decode_from_std_read(&mut bin_file, config).unwrap();
+            match disasm::disassemble(&program) {
+                Ok(text) => print!("{}", text),
+                Err(err) => {
+                    eprintln!("[DISASM]: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
 
     if status {
-        matches.subcommand_matches("com").map(|m| {
+        if let Some(m) = matches.subcommand_matches("com") {
             status = false;
             let file = m.value_of("SOURCE").unwrap();
             println!("source: {}", file);
-        });
+
+            let content = fs::read_to_string(file).unwrap();
+            let forms = match parser::parse_program(&content) {
+                Ok(forms) => forms,
+                Err(err) => {
+                    eprintln!("{}", diagnostic::render(&content, file, "PARSER", &err.to_string(), err.span()));
+                    std::process::exit(1);
+                }
+            };
+            let dir = Path::new(file).parent().unwrap_or(Path::new("."));
+            let program = match GenEnv::new().set_dir(dir).generate_program(&forms) {
+                Ok(program) => program,
+                Err(err) => {
+                    eprintln!("{}", diagnostic::render(&content, file, "CODEGEN", &err.to_string(), Some(err.span())));
+                    std::process::exit(1);
+                }
+            };
+
+            let output_filename = Path::new(file).with_extension("abin");
+            write_bin(program, &output_filename.to_string_lossy(), config);
+        }
     }
 
     if status {
-        matches.subcommand_matches("repl").map(|m| {
-            status = false;
+        if let Some(m) = matches.subcommand_matches("repl") {
             repl::repl();
-        });
+        }
     }
 
 }
@@ -14,6 +14,8 @@ pub enum ByteCode {
     Get(usize),
     Set(usize),
     Arg(usize),  // for calling lambda function, like a relative get
+    LoadLocal(usize),  // relative to the current frame
+    StoreLocal(usize),
 
     Jmp(usize),
     PopJmpIf(usize),
@@ -43,9 +45,34 @@ pub enum ByteCode {
     Not,
     Xor,
 
+    Print,  // pops and writes to the output of VM
+    PrintLn,
+    ReadLine,  // pushes a Str without the newline, or nil at EOF
+    Exit,  // pops the exit code and halts
+
     // for lisp
     CollectList(usize),
-    CollectCharList(usize),
-    CallTopFn,  // the number of parameter is based on the argc of Func, Func should be on top of the stack
+    CollectCharList(usize),  // pops chars, pushes a Str
+    Concat,  // two Str, or two lists
+    Len,  // of a Str or a list
+    CharAt,  // the index on top, the Str below it
+    Substr,  // Str, start, end(exclusive) -> Str, counts chars
+    StrToList,  // Str -> list of Char
+    ListToStr,  // list of Char -> Str
+    Car,  // the first element, nil for an empty list
+    Cdr,  // the rest, empty for an empty list
+    Cons,  // the element below the list
+    IsNil,  // nil or an empty list
+    IsList,  // nil or a list
+    NoMatch,  // pops the value no clause of match took, and fails
+    CallTopFn(usize),  // the number of arguments passed, must be the argc of Func, Func should be on top of the stack
+    TailCallTopFn(usize),  // like CallTopFn, but replaces the current frame
+    CallNative(usize),  // pops the arity of the native, pushes its result
+    MakeClosure(usize),  // Func on top, the captured values below it
+    LoadCapture(usize),  // from the closure being called
+    LoadRec(usize),  // the Func captured there, closed over the captures of the closure being called
+    MakeCell,  // the value on top goes into a new Cell
+    CellGet,  // the Cell on top -> its value
+    CellSet,  // the Cell on top, the value below it, pops both
 }
 
@@ -0,0 +1,65 @@
+use crate::vm::bytecode::ByteCode;
+use crate::vm::value::Value;
+use std::fmt::{Display, Formatter};
+
+#[derive (Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    StackUnderflow,
+    InvalidAddress,
+    NotAFunction,
+    WrongNumberOfArgument(usize, usize),  // expected, got
+    TypeMismatch(&'static str),
+    DivisionByZero,
+    IndexOutOfRange,
+    Overflow,
+    PcOutOfBounds,
+    Native(String),  // reported by a host function
+    NoMatch(String),  // the value
+    Io(String),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::StackUnderflow  => write!(f, "STACK UNDERFLOW"),
+            ErrorKind::InvalidAddress  => write!(f, "Not a valid address type"),
+            ErrorKind::NotAFunction    => write!(f, "Not a valid function"),
+            ErrorKind::WrongNumberOfArgument(expected, got) =>
+                write!(f, "Expected {} arguments, got {}", expected, got),
+            ErrorKind::TypeMismatch(s) => write!(f, "{}", s),
+            ErrorKind::DivisionByZero  => write!(f, "Division by zero"),
+            ErrorKind::IndexOutOfRange => write!(f, "Index out of range"),
+            ErrorKind::Overflow        => write!(f, "Arithmetic overflow"),
+            ErrorKind::PcOutOfBounds   => write!(f, "Program counter out of bounds"),
+            ErrorKind::Native(s)       => write!(f, "{}", s),
+            ErrorKind::NoMatch(s)      => write!(f, "No clause matches {}", s),
+            ErrorKind::Io(s)           => write!(f, "IO error: {}", s),
+        }
+    }
+}
+
+// everything the host needs to know about what went wrong,
+// so a bad program never takes it down
+#[derive (Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub pc: usize,
+    pub byte: Option<ByteCode>,  // None if pc is out of the program
+    pub stack: Vec<Value>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[RUNTIME]: {} at pc {}", self.kind, self.pc)?;
+        if let Some(byte) = &self.byte {
+            write!(f, " ({:?})", byte)?;
+        }
+        write!(f, "\nstack: ")?;
+        for i in &self.stack {
+            write!(f, "|{:?}", i)?;
+        }
+        write!(f, "|")
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -1,43 +1,99 @@
 use crate::vm::bytecode::ByteCode;
 use crate::vm::value::Value;
 use crate::vm::object::ObjType;
+use crate::vm::error::{ErrorKind, RuntimeError};
+use crate::vm::native::{Native, NativeFn};
 use crate::builtin::linkedlist::List;
 
 use std::{thread, time};
-use std::io::Write;
+use std::io::{self, Write, BufRead, BufReader};
+use std::fmt::{Debug, Formatter};
+use std::cell::RefCell;
 use std::rc::Rc;
 use console::{Term, Key};
 
+const NOT_A_LIST: &str = "[VM]: Not a list";
+const NOT_A_STR: &str = "[VM]: Not a string";
+const NOT_A_CHAR: &str = "[VM]: Not a char";
 
+
+fn to_char(val: Value) -> Result<char, ErrorKind> {
+    match val {
+        Value::Char(c) => char::from_u32(c).ok_or(ErrorKind::TypeMismatch(NOT_A_CHAR)),
+        _ => Err(ErrorKind::TypeMismatch(NOT_A_CHAR)),
+    }
+}
+
+// what to do with pc after a step
+enum Flow {
+    Next,
+    Jump(usize),
+    Halt,
+    Call(Rc<ObjType>),
+    TailCall(Rc<ObjType>),
+}
+
+// one per CallTopFn, arguments and locals live in VM.locals from base
 #[derive(Debug)]
+pub struct Frame {
+    pub ret: usize,  // pc of the CallTopFn
+    pub base: usize,
+    caller: Option<Rc<ObjType>>,  // code to return to, None is the program passed to run
+    callee: Rc<ObjType>,
+}
+
 pub struct VM {
     pub stack: Vec<Value>,
     pub pc: usize,  // program counter
     pub constant_pool: Vec<Value>,
+    pub frames: Vec<Frame>,
+    pub locals: Vec<Value>,
+    pub natives: Vec<Native>,
+    pub exit_code: Option<i64>,  // set by Exit
 
+    output: Box<dyn Write>,
+    input: Box<dyn BufRead>,
     delay: u64,
     render: bool,
     debug: bool,
 }
 
-impl VM {
-    pub fn default() -> Self {
-        VM {
-            stack: Vec::with_capacity(256),
-            pc: 0,
-            constant_pool: Vec::with_capacity(16),
-            delay: 100,
-            render: false,
-            debug: false,
-        }
+impl Default for VM {
+    fn default() -> Self {
+        VM::new(100, false)
+    }
+}
+
+impl Debug for VM {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VM")
+            .field("stack", &self.stack)
+            .field("pc", &self.pc)
+            .field("constant_pool", &self.constant_pool)
+            .field("frames", &self.frames)
+            .field("locals", &self.locals)
+            .field("natives", &self.natives)
+            .field("exit_code", &self.exit_code)
+            .field("delay", &self.delay)
+            .field("render", &self.render)
+            .field("debug", &self.debug)
+            .finish()
     }
+}
 
+impl VM {
     pub fn new(delay: u64, render: bool) -> Self {
         // https://doc.rust-lang.org/std/vec/struct.Vec.html#capacity-and-reallocation
         VM {
             stack: Vec::with_capacity(256),
             pc: 0,
             constant_pool: Vec::with_capacity(16),
+            frames: Vec::with_capacity(16),
+            locals: Vec::with_capacity(64),
+            natives: vec![],
+            exit_code: None,
+            output: Box::new(io::stdout()),
+            input: Box::new(BufReader::new(io::stdin())),
             debug: false,
             delay,
             render,
@@ -49,211 +105,540 @@ impl VM {
         self
     }
 
+    pub fn is_debug(&self) -> bool {
+        self.debug
+    }
+
+    // where Print and PrintLn write to, stdout by default
+    pub fn set_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    // where ReadLine reads from, stdin by default
+    pub fn set_input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = Box::new(input);
+        self
+    }
+
+    // returns the index for CallNative, registering a name again replaces it
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, func: F) -> usize
+    where F: Fn(&mut VM, &[Value]) -> Result<Value, ErrorKind> + 'static {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            func: Rc::new(func) as Rc<NativeFn>,
+        };
+        match self.native_index(name) {
+            Some(index) => {
+                self.natives[index] = native;
+                index
+            }
+            None => {
+                self.natives.push(native);
+                self.natives.len() - 1
+            }
+        }
+    }
+
+    pub fn native_index(&self, name: &str) -> Option<usize> {
+        self.natives.iter().position(|native| native.name == name)
+    }
+
     pub fn reset_pc(&mut self) {
         self.pc = 0;
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().expect("[RUNTIME]: STACK UNDERFLOW")
+    fn pop(&mut self) -> Result<Value, ErrorKind> {
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow)
     }
 
+    fn top(&mut self) -> Result<&mut Value, ErrorKind> {
+        self.stack.last_mut().ok_or(ErrorKind::StackUnderflow)
+    }
+
+    // relative to the top, peek(0) is the top
+    fn peek(&self, relative_index: usize) -> Result<&Value, ErrorKind> {
+        if relative_index < self.stack.len() {
+            Ok(&self.stack[self.stack.len() - 1 - relative_index])
+        } else {
+            Err(ErrorKind::StackUnderflow)
+        }
+    }
+
+    // nil is the empty list
+    fn pop_list(&mut self) -> Result<List<Value>, ErrorKind> {
+        match self.pop()? {
+            Value::Nil => Ok(List::new()),
+            Value::Ref(rf) => match &*rf {
+                ObjType::Cons(list) => Ok(list.clone()),
+                _ => Err(ErrorKind::TypeMismatch(NOT_A_LIST)),
+            },
+            _ => Err(ErrorKind::TypeMismatch(NOT_A_LIST)),
+        }
+    }
+
+    fn pop_str(&mut self) -> Result<String, ErrorKind> {
+        match self.pop()? {
+            Value::Ref(rf) => rf.as_str().map(str::to_string).ok_or(ErrorKind::TypeMismatch(NOT_A_STR)),
+            _ => Err(ErrorKind::TypeMismatch(NOT_A_STR)),
+        }
+    }
+
+    fn pop_index(&mut self) -> Result<usize, ErrorKind> {
+        match self.pop()? {
+            Value::Int(i) => usize::try_from(i).map_err(|_| ErrorKind::IndexOutOfRange),
+            _ => Err(ErrorKind::TypeMismatch("[VM]: Index should be an Int")),
+        }
+    }
+
+    fn push_str(&mut self, s: String) {
+        self.stack.push(Value::Ref(Rc::new(ObjType::Str(s))));
+    }
+
+    // the top level program uses locals from 0
+    fn frame_base(&self) -> usize {
+        self.frames.last().map(|frame| frame.base).unwrap_or(0)
+    }
+
+    fn compare(&mut self, func: &dyn Fn(&Value, Value) -> Result<bool, ErrorKind>) -> Result<(), ErrorKind> {
+        // comparation keeps both operands on the stack
+        let b = self.peek(0)?.clone();
+        let a = self.peek(1)?;
+        let result = func(a, b)?;
+        self.stack.push(Value::Bool(result));
+        Ok(())
+    }
+
+    pub fn run(&mut self, program: &[ByteCode]) -> Result<(), RuntimeError> {
+        let depth = self.frames.len();
+        let result = self.exec(program, depth);
+        // unwind the frames of the calls a HALT or an error left behind
+        if let Some(frame) = self.frames.get(depth) {
+            self.locals.truncate(frame.base);
+        }
+        self.frames.truncate(depth);
+        result
+    }
+
+    // function bodies are executed in place, calling one only pushes a Frame,
+    // so neither the bytecode nor the rust stack grows with the call depth
     #[inline (always)]
-    pub fn run(&mut self, program: &Vec<ByteCode>) {
+    fn exec(&mut self, program: &[ByteCode], depth: usize) -> Result<(), RuntimeError> {
+        let mut current: Option<Rc<ObjType>> = None;
         loop {
-            let byte = &program[self.pc];
-            match byte {
-                ByteCode::HALT => break,
-                ByteCode::Push(value) => self.stack.push(value.clone()),
-                ByteCode::Pop => {self.pop();}
-                ByteCode::Dup => {
-                    let a = self.stack.last().unwrap();
-                    self.stack.push(a.clone());
+            let code = match &current {
+                Some(func) => func.body().expect("[RUNTIME]: Called a non-function"),
+                None => program,
+            };
+            let byte = match code.get(self.pc) {
+                Some(byte) => byte,
+                // running off the end of a function body returns from it
+                None if self.frames.len() > depth && self.pc == code.len() => {
+                    current = self.ret();
+                    continue
                 }
-                ByteCode::Copy(relative_index) => {
-                    // from tsoding live 1 fibonacci
-                    // Copy(0) == Dup
-                    let val = &self.stack[self.stack.len() - 1 - *relative_index];
-                    self.stack.push(val.clone());
+                None => return Err(RuntimeError {
+                    kind: ErrorKind::PcOutOfBounds,
+                    pc: self.pc,
+                    byte: None,
+                    stack: self.stack.clone(),
+                }),
+            };
+            let flow = match self.step(byte) {
+                Ok(flow) => flow,
+                Err(kind) => return Err(RuntimeError {
+                    kind,
+                    pc: self.pc,
+                    byte: Some(byte.clone()),
+                    stack: self.stack.clone(),
+                }),
+            };
+            if self.render {
+                self.render(byte, self.delay, self.debug, Term::stdout());
+            }
+            match flow {
+                Flow::Next => self.pc += 1,
+                Flow::Jump(pc) => self.pc = pc,
+                Flow::Halt => return Ok(()),
+                Flow::TailCall(func) if self.frames.len() > depth => {
+                    // reuse the frame, the caller's locals are dead now
+                    let argc = func.argc().expect("[RUNTIME]: Called a non-function");
+                    let frame = self.frames.last_mut().expect("[RUNTIME]: Frame stack error");
+                    self.locals.truncate(frame.base);
+                    let args_start = self.stack.len() - argc;
+                    self.locals.extend(self.stack.drain(args_start..));
+                    frame.callee = func.clone();
+                    current = Some(func);
+                    self.reset_pc();
                 }
-                ByteCode::Swap => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.stack.push(b);
-                    self.stack.push(a);
+                // a tail call at the top level has no frame to reuse
+                Flow::Call(func) | Flow::TailCall(func) => {
+                    let argc = func.argc().expect("[RUNTIME]: Called a non-function");
+                    let base = self.locals.len();
+                    let args_start = self.stack.len() - argc;  // checked by CallTopFn
+                    self.locals.extend(self.stack.drain(args_start..));
+                    self.frames.push(Frame { ret: self.pc, base, caller: current.take(), callee: func.clone() });
+                    current = Some(func);
+                    self.reset_pc();
                 }
+            }
+        }
+    }
 
-                ByteCode::Set(index) => {
-                    if self.constant_pool.len() > *index {
-                        self.constant_pool[*index] = self.pop();
-                    } else {
-                        let val = self.pop();
-                        self.constant_pool.insert(*index, val);
-                    }
-                },
-                ByteCode::Get(index) => self.stack.push(self.constant_pool[*index].clone()),
-
-                ByteCode::Jmp(pc) => self.pc = pc - 1,  // because pc will increase at the end of loop
-                ByteCode::PopJmpIf(pc) => {
-                    if *self.stack.last().unwrap() == Value::Bool(true) {
-                        self.stack.pop();
-                        self.pc = pc - 1;
-                    }
-                }
-                ByteCode::PopJmpIfNot(pc) => {
-                    if *self.stack.last().unwrap() == Value::Bool(false) {
-                        self.stack.pop();
-                        self.pc = pc - 1;
-                    }
-                }
+    // pop the frame, free its locals, and give back the code of the caller
+    fn ret(&mut self) -> Option<Rc<ObjType>> {
+        let frame = self.frames.pop().expect("[RUNTIME]: Frame stack error");
+        self.locals.truncate(frame.base);
+        self.pc = frame.ret + 1;
+        frame.caller
+    }
 
-                ByteCode::Call(pc) => {
-                    self.stack.push(Value::Int(self.pc as i64));
-                    self.pc = pc - 1;
-                }
-                ByteCode::Ret => {
-                    let addr = match self.pop() {
-                        Value::Int(n) => n as usize,
-                        _ => panic!("[RUNTIME]: Not a valid address type"),
-                    };
-                    self.pc = addr;
-                    // it should't - 1, otherwise will loop infinetly
-                }
+    fn step(&mut self, byte: &ByteCode) -> Result<Flow, ErrorKind> {
+        match byte {
+            ByteCode::HALT => return Ok(Flow::Halt),
+            ByteCode::Push(value) => self.stack.push(value.clone()),
+            ByteCode::Pop => {self.pop()?;}
+            ByteCode::Dup => {
+                let a = self.peek(0)?.clone();
+                self.stack.push(a);
+            }
+            ByteCode::Copy(relative_index) => {
+                // from tsoding live 1 fibonacci
+                // Copy(0) == Dup
+                let val = self.peek(*relative_index)?.clone();
+                self.stack.push(val);
+            }
+            ByteCode::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(b);
+                self.stack.push(a);
+            }
 
-                // normally it should pop two and push one,
-                // but I want to resuce the number of operation
-                ByteCode::Add => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();  // no need to "let mut a"
-                    a.add(b);
-                }
-                ByteCode::Inc => {
-                    let a = self.stack.last_mut().unwrap();
-                    a.add(Value::Int(1));
-                }
-                ByteCode::Sub => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.sub(b);
-                }
-                ByteCode::Dec => {
-                    let a = self.stack.last_mut().unwrap();
-                    a.sub(Value::Int(1));
-                }
-                ByteCode::Mul => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.mul(b);
-                }
-                ByteCode::Div => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.div(b);
-                }
-                ByteCode::Rem => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.rem(b);
-                }
-                ByteCode::And => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.and(b);
-                }
-                ByteCode::Or => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.or(b);
-                }
-                ByteCode::Xor => {
-                    let b = self.pop();
-                    let a = self.stack.last_mut().unwrap();
-                    a.xor(b);
-                }
-                ByteCode::Not => {
-                    let a = self.stack.last_mut().unwrap();
-                    a.not();
+            ByteCode::Set(index) => {
+                let val = self.pop()?;
+                // slots are given out when compiling, a def that didn't run leaves a gap
+                if *index >= self.constant_pool.len() {
+                    self.constant_pool.resize(index + 1, Value::Nil);
                 }
+                self.constant_pool[*index] = val;
+            },
+            ByteCode::Get(index) => {
+                let val = self.constant_pool.get(*index).ok_or(ErrorKind::InvalidAddress)?.clone();
+                self.stack.push(val);
+            }
 
-                ByteCode::Greater => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(a.gt(b.clone())));
-                }
-                ByteCode::GreaterEq => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(! a.lt(b.clone())));
+            // relative to the current frame, arguments are the first locals
+            ByteCode::Arg(index) | ByteCode::LoadLocal(index) => {
+                let val = self.locals.get(self.frame_base() + index).ok_or(ErrorKind::InvalidAddress)?.clone();
+                self.stack.push(val);
+            }
+            ByteCode::StoreLocal(index) => {
+                let val = self.pop()?;
+                let index = self.frame_base() + index;
+                if index >= self.locals.len() {
+                    self.locals.resize(index + 1, Value::Nil);
                 }
-                ByteCode::Less => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(a.lt(b.clone())));
+                self.locals[index] = val;
+            }
+
+            ByteCode::Jmp(pc) => return Ok(Flow::Jump(*pc)),
+            ByteCode::PopJmpIf(pc) => {
+                if *self.peek(0)? == Value::Bool(true) {
+                    self.stack.pop();
+                    return Ok(Flow::Jump(*pc))
                 }
-                ByteCode::LessEq => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(! a.gt(b.clone())));
+            }
+            ByteCode::PopJmpIfNot(pc) => {
+                if *self.peek(0)? == Value::Bool(false) {
+                    self.stack.pop();
+                    return Ok(Flow::Jump(*pc))
                 }
-                ByteCode::Eq => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(a.eq(b.clone())));
+            }
+
+            ByteCode::Call(pc) => {
+                self.stack.push(Value::Int(self.pc as i64));
+                return Ok(Flow::Jump(*pc))
+            }
+            ByteCode::Ret => {
+                let addr = match self.pop()? {
+                    Value::Int(n) if n >= 0 => n as usize,
+                    _ => return Err(ErrorKind::InvalidAddress),
+                };
+                // continue after the call instruction
+                return Ok(Flow::Jump(addr + 1))
+            }
+
+            // normally it should pop two and push one,
+            // but I want to resuce the number of operation
+            ByteCode::Add => {
+                let b = self.pop()?;
+                self.top()?.add(b)?;  // no need to "let mut a"
+            }
+            ByteCode::Inc => self.top()?.add(Value::Int(1))?,
+            ByteCode::Sub => {
+                let b = self.pop()?;
+                self.top()?.sub(b)?;
+            }
+            ByteCode::Dec => self.top()?.sub(Value::Int(1))?,
+            ByteCode::Mul => {
+                let b = self.pop()?;
+                self.top()?.mul(b)?;
+            }
+            ByteCode::Div => {
+                let b = self.pop()?;
+                self.top()?.div(b)?;
+            }
+            ByteCode::Rem => {
+                let b = self.pop()?;
+                self.top()?.rem(b)?;
+            }
+            ByteCode::And => {
+                let b = self.pop()?;
+                self.top()?.and(b)?;
+            }
+            ByteCode::Or => {
+                let b = self.pop()?;
+                self.top()?.or(b)?;
+            }
+            ByteCode::Xor => {
+                let b = self.pop()?;
+                self.top()?.xor(b)?;
+            }
+            ByteCode::Not => self.top()?.not()?,
+
+            ByteCode::Greater   => self.compare(&|a, b| a.gt(b))?,
+            ByteCode::GreaterEq => self.compare(&|a, b| Ok(! a.lt(b)?))?,
+            ByteCode::Less      => self.compare(&|a, b| a.lt(b))?,
+            ByteCode::LessEq    => self.compare(&|a, b| Ok(! a.gt(b)?))?,
+            ByteCode::Eq        => self.compare(&|a, b| a.equals(b))?,
+            ByteCode::Neq       => self.compare(&|a, b| Ok(! a.equals(b)?))?,
+            ByteCode::Seq       => self.compare(&|a, b| Ok(*a == b))?,
+            ByteCode::Sneq      => self.compare(&|a, b| Ok(*a != b))?,
+
+            ByteCode::CollectList(n) => {
+                let mut list = List::new();
+                // stack(-> top): 1 2 3 4 5
+                // list: 1 2 3 4 5
+                for _i in 1..=*n {
+                    list = list.prepend(self.pop()?);
                 }
-                ByteCode::Neq => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(! a.eq(b.clone())));
+                self.stack.push(Value::Ref(Rc::new(ObjType::Cons(list))));
+            }
+
+            ByteCode::CollectCharList(n) => {
+                if *n > self.stack.len() {
+                    return Err(ErrorKind::StackUnderflow)
                 }
-                ByteCode::Seq => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(*a == b.clone()));
+                let chars = self.stack.split_off(self.stack.len() - n);
+                let s = chars.into_iter().map(to_char).collect::<Result<String, _>>()?;
+                self.push_str(s);
+            }
+            ByteCode::Concat => {
+                if let Value::Ref(rf) = self.peek(0)? {
+                    if let ObjType::Str(_) = &**rf {
+                        let b = self.pop_str()?;
+                        let a = self.pop_str()?;
+                        self.push_str(a + &b);
+                        return Ok(Flow::Next)
+                    }
                 }
-                ByteCode::Sneq => {
-                    let b = self.stack.last().unwrap();
-                    let a = &self.stack[self.stack.len() - 2];
-                    self.stack.push(Value::Bool(*a != b.clone()));
+                let b = self.pop_list()?;
+                let a = self.pop_list()?;
+                let elems: Vec<&Value> = a.iter().collect();
+                let list = elems.into_iter().rev().fold(b, |list, elem| list.prepend(elem.clone()));
+                self.stack.push(Value::Ref(Rc::new(ObjType::Cons(list))));
+            }
+            ByteCode::Len => {
+                let len = match self.pop()? {
+                    Value::Ref(rf) => match &*rf {
+                        ObjType::Str(s) => s.chars().count(),
+                        ObjType::Cons(list) => list.iter().count(),
+                        _ => return Err(ErrorKind::TypeMismatch("[VM]: Not a string or list")),
+                    },
+                    Value::Nil => 0,
+                    _ => return Err(ErrorKind::TypeMismatch("[VM]: Not a string or list")),
+                };
+                self.stack.push(Value::Int(len as i64));
+            }
+            ByteCode::CharAt => {
+                let index = self.pop_index()?;
+                let s = self.pop_str()?;
+                let c = s.chars().nth(index).ok_or(ErrorKind::IndexOutOfRange)?;
+                self.stack.push(Value::Char(c as u32));
+            }
+            ByteCode::Substr => {
+                let end = self.pop_index()?;
+                let start = self.pop_index()?;
+                let s = self.pop_str()?;
+                if start > end || end > s.chars().count() {
+                    return Err(ErrorKind::IndexOutOfRange)
                 }
+                self.push_str(s.chars().skip(start).take(end - start).collect());
+            }
+            ByteCode::StrToList => {
+                let s = self.pop_str()?;
+                let list = s.chars().rev()
+                    .fold(List::new(), |list, c| list.prepend(Value::Char(c as u32)));
+                self.stack.push(Value::Ref(Rc::new(ObjType::Cons(list))));
+            }
+            ByteCode::ListToStr => {
+                let list = self.pop_list()?;
+                let s = list.iter().cloned().map(to_char).collect::<Result<String, _>>()?;
+                self.push_str(s);
+            }
 
-                ByteCode::CollectList(n) => {
-                    let mut list = List::new();
-                    // stack(-> top): 1 2 3 4 5
-                    // list: 1 2 3 4 5
-                    for _i in 1..=*n {
-                        list = list.prepend(self.pop());
+            ByteCode::Car => {
+                let list = self.pop_list()?;
+                self.stack.push(list.head().cloned().unwrap_or(Value::Nil));
+            }
+            ByteCode::Cdr => {
+                let list = self.pop_list()?;
+                self.stack.push(Value::Ref(Rc::new(ObjType::Cons(list.tail()))));
+            }
+            ByteCode::Cons => {
+                let list = self.pop_list()?;
+                let elem = self.pop()?;
+                self.stack.push(Value::Ref(Rc::new(ObjType::Cons(list.prepend(elem)))));
+            }
+            ByteCode::IsNil => {
+                let is_nil = match self.pop()? {
+                    Value::Nil => true,
+                    Value::Ref(rf) => matches!(&*rf, ObjType::Cons(list) if list.head().is_none()),
+                    _ => false,
+                };
+                self.stack.push(Value::Bool(is_nil));
+            }
+            ByteCode::IsList => {
+                let is_list = match self.pop()? {
+                    Value::Nil => true,
+                    Value::Ref(rf) => matches!(&*rf, ObjType::Cons(_)),
+                    _ => false,
+                };
+                self.stack.push(Value::Bool(is_list));
+            }
+            ByteCode::NoMatch => {
+                let val = self.pop()?;
+                return Err(ErrorKind::NoMatch(format!("{:?}", val)))
+            }
+
+            ByteCode::CallTopFn(n) | ByteCode::TailCallTopFn(n) => {
+                if let Value::Ref(rf) = self.pop()? {
+                    match rf.argc() {
+                        Some(argc) if argc != *n => return Err(ErrorKind::WrongNumberOfArgument(argc, *n)),
+                        Some(argc) if argc > self.stack.len() => return Err(ErrorKind::StackUnderflow),
+                        Some(_argc) if matches!(byte, ByteCode::TailCallTopFn(_)) => return Ok(Flow::TailCall(rf)),
+                        Some(_argc) => return Ok(Flow::Call(rf)),
+                        None => return Err(ErrorKind::NotAFunction),
                     }
-                    self.stack.push(Value::Ref(Rc::new(ObjType::Cons(list))));
+                } else {
+                    return Err(ErrorKind::NotAFunction)
                 }
-
-                ByteCode::CallTopFn => {
-                    if let Value::Ref(rf) = self.pop() {
-                        match &*rf {
-                            ObjType::Func(argc, body) => {
-                                let current_pool_index = self.constant_pool.len() - 1;
-                                let function_bytecode = expand_function_bytecode(*argc, current_pool_index, &body);
-                                let pc = self.pc;
-                                //println!("{:?}\n{:?}", &function_bytecode, &self);
-                                self.reset_pc();
-                                self.run(&function_bytecode);
-                                self.pc = pc;
-                            }
-                            _ => panic!("[RUNTIME]: Not a valid function")
-                        }
-                    } else {
-                        panic!("[RUNTIME]: Not a valid function")
+            }
+            ByteCode::CallNative(index) => {
+                let native = self.natives.get(*index).ok_or(ErrorKind::InvalidAddress)?.clone();
+                if native.arity > self.stack.len() {
+                    return Err(ErrorKind::StackUnderflow)
+                }
+                let args = self.stack.split_off(self.stack.len() - native.arity);
+                let result = (native.func)(self, &args)?;
+                self.stack.push(result);
+            }
+            ByteCode::Print => {
+                let val = self.pop()?;
+                write!(self.output, "{}", val).map_err(io_error)?;
+                self.output.flush().map_err(io_error)?;
+            }
+            ByteCode::PrintLn => {
+                let val = self.pop()?;
+                writeln!(self.output, "{}", val).map_err(io_error)?;
+            }
+            ByteCode::ReadLine => {
+                let mut line = String::new();
+                let val = match self.input.read_line(&mut line).map_err(io_error)? {
+                    0 => Value::Nil,  // EOF
+                    _ => {
+                        let line = line.strip_suffix('\n').unwrap_or(&line);
+                        let line = line.strip_suffix('\r').unwrap_or(line);
+                        Value::Ref(Rc::new(ObjType::Str(line.to_string())))
                     }
+                };
+                self.stack.push(val);
+            }
+            ByteCode::Exit => {
+                let code = match self.pop()? {
+                    Value::Int(code) => code,
+                    _ => return Err(ErrorKind::TypeMismatch("[RUNTIME]: Exit code should be Value::Int")),
+                };
+                self.exit_code = Some(code);
+                return Ok(Flow::Halt)
+            }
+
+            ByteCode::MakeClosure(n) => {
+                let func = match self.pop()? {
+                    Value::Ref(rf) if matches!(*rf, ObjType::Func(..)) => rf,
+                    _ => return Err(ErrorKind::NotAFunction),
+                };
+                if *n > self.stack.len() {
+                    return Err(ErrorKind::StackUnderflow)
                 }
-                _ => todo!("wtf!"),
+                let captured = self.stack.split_off(self.stack.len() - n);
+                self.stack.push(Value::Ref(Rc::new(ObjType::Closure(func, captured))));
             }
-            if self.render {
-                self.render(byte, self.delay, self.debug, Term::stdout());
+            ByteCode::LoadCapture(index) => {
+                let val = self.frames.last()
+                    .and_then(|frame| frame.callee.captured().get(*index))
+                    .ok_or(ErrorKind::InvalidAddress)?
+                    .clone();
+                self.stack.push(val);
+            }
+            ByteCode::MakeCell => {
+                let value = self.pop()?;
+                self.stack.push(Value::Ref(Rc::new(ObjType::Cell(RefCell::new(value)))));
+            }
+            ByteCode::CellGet => {
+                let value = match self.pop()? {
+                    Value::Ref(rf) => match &*rf {
+                        ObjType::Cell(value) => value.borrow().clone(),
+                        _ => return Err(ErrorKind::InvalidAddress),
+                    },
+                    _ => return Err(ErrorKind::InvalidAddress),
+                };
+                self.stack.push(value);
+            }
+            ByteCode::CellSet => {
+                let cell = self.pop()?;
+                let value = self.pop()?;
+                match cell {
+                    Value::Ref(rf) => match &*rf {
+                        ObjType::Cell(cell) => *cell.borrow_mut() = value,
+                        _ => return Err(ErrorKind::InvalidAddress),
+                    },
+                    _ => return Err(ErrorKind::InvalidAddress),
+                }
+            }
+            // the functions of a letrec capture each other as plain Funcs and share the rest,
+            // so one of them is made a closure again when it is used, without Rc cycles
+            ByteCode::LoadRec(index) => {
+                let callee = &self.frames.last().ok_or(ErrorKind::InvalidAddress)?.callee;
+                let func = match callee.captured().get(*index) {
+                    Some(Value::Ref(rf)) if matches!(**rf, ObjType::Func(..)) => rf,
+                    _ => return Err(ErrorKind::InvalidAddress),
+                };
+                let closure = match &**callee {
+                    // calling itself
+                    ObjType::Closure(own, _) if Rc::ptr_eq(own, func) => callee.clone(),
+                    _ => Rc::new(ObjType::Closure(func.clone(), callee.captured().to_vec())),
+                };
+                self.stack.push(Value::Ref(closure));
             }
-            self.pc += 1;
         }
+        Ok(Flow::Next)
     }
 
     pub fn render(&mut self, byte: &ByteCode, delay: u64, debug: bool, mut term: Term) {
-        write!(term, "{:?}\n", byte).unwrap();
+        writeln!(term, "{:?}", byte).unwrap();
         for i in &self.stack {
             write!(term, "|{:?}", i).unwrap();
         }
@@ -270,7 +655,7 @@ impl VM {
                     _ => (),
                 }
             }
-            write!(term, "\n").unwrap();
+            writeln!(term).unwrap();
         } else {
             thread::sleep(time::Duration::from_millis(delay));
             term.clear_line().unwrap();
@@ -279,29 +664,6 @@ impl VM {
     }
 }
 
-
-// Calltopfn helper function
-// Very ELEGANT
-fn expand_function_bytecode(argc: usize, current_pool_index: usize, body: &Vec<ByteCode>) -> Vec<ByteCode> {
-    (1..=argc)
-    // don't need to .iter()
-        .map(|i| {
-            vec![ByteCode::Copy(argc - i),
-                 ByteCode::Set(current_pool_index + i)]
-        })
-        .flatten()
-        .chain(
-            body
-                .iter()
-                .map(|byte| {
-                    match byte {
-                        ByteCode::Arg(n) => ByteCode::Get(current_pool_index + n + 1),
-                        _ => byte.clone(),
-                    }
-                })
-        )
-        .chain(
-            std::iter::once(ByteCode::HALT)
-        )
-        .collect::<Vec<ByteCode>>()
+fn io_error(err: io::Error) -> ErrorKind {
+    ErrorKind::Io(err.to_string())
 }
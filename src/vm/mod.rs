@@ -2,3 +2,5 @@ pub mod machine;
 pub mod bytecode;
 pub mod value;
 pub mod object;
+pub mod error;
+pub mod native;
@@ -0,0 +1,21 @@
+use crate::vm::machine::VM;
+use crate::vm::value::Value;
+use crate::vm::error::ErrorKind;
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+pub type NativeFn = dyn Fn(&mut VM, &[Value]) -> Result<Value, ErrorKind>;
+
+// a function provided by the host, called with CallNative(index)
+#[derive (Clone)]
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub func: Rc<NativeFn>,
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native {}/{}>", self.name, self.arity)
+    }
+}
@@ -2,7 +2,9 @@ use bincode::{Encode, Decode};
 use crate::vm::value::Value;
 use crate::vm::bytecode::ByteCode;
 use crate::builtin::linkedlist::List;
+use std::cell::RefCell;
 use std::fmt::{Display, Formatter};
+use std::rc::Rc;
 
 // it seems bincode cannot encode/decode Linkedlist
 // so maybe I should implment a Linkedlist myself
@@ -11,7 +13,42 @@ use std::fmt::{Display, Formatter};
 pub enum ObjType {
     Cons(List<Value>),
     Func(usize, Vec<ByteCode>),
+    Closure(Rc<ObjType>, Vec<Value>),  // Func and the values it captured
     Str(String),
+    Sym(String),  // quoted symbol
+    Cell(RefCell<Value>),  // a variable that is set and captured, shared by the frame and the closures
+}
+
+impl ObjType {
+    pub fn argc(&self) -> Option<usize> {
+        match self {
+            ObjType::Func(argc, _body) => Some(*argc),
+            ObjType::Closure(func, _captured) => func.argc(),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> Option<&[ByteCode]> {
+        match self {
+            ObjType::Func(_argc, body) => Some(body),
+            ObjType::Closure(func, _captured) => func.body(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ObjType::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn captured(&self) -> &[Value] {
+        match self {
+            ObjType::Closure(_func, captured) => captured,
+            _ => &[],
+        }
+    }
 }
 
 impl Display for ObjType {
@@ -19,7 +56,10 @@ impl Display for ObjType {
         match self {
             ObjType::Cons(l) => write!(f, "{:?}", l),
             ObjType::Func(_argc, body) => write!(f, "{:?}", body),
+            ObjType::Closure(func, captured) => write!(f, "{} {:?}", func, captured),
             ObjType::Str(s) => write!(f, "{:?}", s),
+            ObjType::Sym(s) => write!(f, "{}", s),
+            ObjType::Cell(value) => write!(f, "{}", value.borrow()),
         }
     }
 }
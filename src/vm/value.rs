@@ -1,7 +1,8 @@
 use std::rc::Rc;
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use bincode::{Encode, Decode};
 use crate::vm::object::ObjType;
+use crate::vm::error::ErrorKind;
 
 #[derive(Clone, PartialEq, Encode, Decode)]
 pub enum Value {
@@ -26,172 +27,235 @@ impl Debug for Value {
     }
 }
 
-const LOGICAL_WRONG_TYPE: &'static str = "[VALUE]: Wrong type for logical computing";
-const COMPARE_WRONG_TYPE: &'static str = "[VALUE]: Wrong type for comparation";
+// for Print, strings and chars are written as they are
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Float(i) => write!(f, "{}", i),
+            Value::Char(i) => write!(f, "{}", std::char::from_u32(*i).unwrap_or(char::REPLACEMENT_CHARACTER)),
+            Value::Ref(rf) => match &**rf {
+                ObjType::Str(s) => write!(f, "{}", s),
+                obj => write!(f, "{}", obj),
+            },
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+const LOGICAL_WRONG_TYPE: &str = "[VALUE]: Wrong type for logical computing";
+const COMPARE_WRONG_TYPE: &str = "[VALUE]: Wrong type for comparation";
+const ARITHMETIC_WRONG_TYPE: &str = "[VALUE]: Wrong type for arithmetical computing";
+const CHAR_WRONG_VALUE: &str = "[VALUE]: Cannot convert to Value::Char";
 
 impl Value {
-    fn try_into_int(self) -> i64 {
+    fn try_into_int(self) -> Result<i64, ErrorKind> {
+        match self {
+            Value::Int(val) => Ok(val),
+            Value::Float(val) => Ok(val as i64),
+            Value::Char(val) => Ok(val as i64),
+            Value::Nil => Ok(0_i64),
+            _ => Err(ErrorKind::TypeMismatch("[VALUE]: Cannot convert to Value::Int")),
+        }
+    }
+
+    // strings only compare with strings
+    fn try_as_str(&self) -> Result<&str, ErrorKind> {
         match self {
-            Value::Int(val) => val,
-            Value::Float(val) => val as i64,
-            Value::Char(val) => val as i64,
-            Value::Nil => 0_i64,
-            _ => panic!("[VALUE]: Cannot convert to Value::Int"),
+            Value::Ref(rf) => rf.as_str().ok_or(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE)),
+            _ => Err(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE)),
         }
     }
 
-    fn try_into_float(self) -> f64 {
+    fn try_into_float(self) -> Result<f64, ErrorKind> {
         match self {
-            Value::Float(val) => val,
-            Value::Int(val) => val as f64,
-            Value::Char(val) => val as u8 as f64,
-            Value::Nil => 0f64,
-            _ => panic!("[VALUE]: Cannot convert to Value::Float"),
+            Value::Float(val) => Ok(val),
+            Value::Int(val) => Ok(val as f64),
+            Value::Char(val) => Ok(val as u8 as f64),
+            Value::Nil => Ok(0f64),
+            _ => Err(ErrorKind::TypeMismatch("[VALUE]: Cannot convert to Value::Float")),
         }
     }
 
-    fn try_into_char(self) -> u32 {
+    fn try_into_char(self) -> Result<u32, ErrorKind> {
         match self {
-            Value::Char(val) => val,
-            Value::Int(val) => val as u32,
-            Value::Float(val) => val as u32,
-            _ => panic!("[VALUE]: Cannot convert to Value::Char"),
+            Value::Char(val) => Ok(val),
+            // a negative one would wrap around
+            Value::Int(val) => u32::try_from(val).map_err(|_| ErrorKind::TypeMismatch(CHAR_WRONG_VALUE)),
+            Value::Float(val) if val >= 0.0 && val <= u32::MAX as f64 => Ok(val as u32),
+            _ => Err(ErrorKind::TypeMismatch(CHAR_WRONG_VALUE)),
         }
     }
 
-    fn try_into_bool(self) -> bool {
+    fn try_into_bool(self) -> Result<bool, ErrorKind> {
         match self {
-            Value::Bool(val) => val,
-            _ => panic!("[VALUE]: Cannot convert to Value::Bool")
+            Value::Bool(val) => Ok(val),
+            _ => Err(ErrorKind::TypeMismatch("[VALUE]: Cannot convert to Value::Bool"))
         }
     }
 
+    fn is_zero(&self) -> bool {
+        match self {
+            Value::Int(0) | Value::Char(0) | Value::Nil => true,
+            Value::Float(f) => *f == 0.0,
+            _ => false,
+        }
+    }
+
+    // checked before any arithmetic, so a wrong type is never reported as something else
+    fn check_numeric(&self, val: &Value) -> Result<(), ErrorKind> {
+        let numeric = |v: &Value| matches!(v, Value::Int(_) | Value::Float(_) | Value::Char(_));
+        if numeric(self) && (numeric(val) || matches!(val, Value::Nil)) {
+            Ok(())
+        } else {
+            Err(ErrorKind::TypeMismatch(ARITHMETIC_WRONG_TYPE))
+        }
+    }
+
+    // integer closures return None on overflow
     #[inline (always)]
     fn arithmetic(
         &mut self,
         val: Value,
-        func_int: &dyn Fn(i64, i64) -> i64,
+        func_int: &dyn Fn(i64, i64) -> Option<i64>,
         func_float: &dyn Fn(f64, f64) -> f64,
-        func_uint: &dyn Fn(u32, u32) -> u32,
-    ) {
+        func_uint: &dyn Fn(u32, u32) -> Option<u32>,
+    ) -> Result<(), ErrorKind> {
+        self.check_numeric(&val)?;
         match self {
-            Value::Int(s) => *s = func_int(*s, val.try_into_int()),
-            Value::Float(s) => *s = func_float(*s, val.try_into_float()),
-            Value::Char(s) => *s = func_uint(*s, val.try_into_char()),
-            _ => panic!("[VALUE]: Wrong type for arithmetical computing"),
+            Value::Int(s) => *s = func_int(*s, val.try_into_int()?).ok_or(ErrorKind::Overflow)?,
+            Value::Float(s) => *s = func_float(*s, val.try_into_float()?),
+            Value::Char(s) => *s = func_uint(*s, val.try_into_char()?).ok_or(ErrorKind::Overflow)?,
+            _ => return Err(ErrorKind::TypeMismatch(ARITHMETIC_WRONG_TYPE)),
         }
+        Ok(())
     }
 
     #[inline (always)]
-    pub fn add(&mut self, val: Value) {
+    pub fn add(&mut self, val: Value) -> Result<(), ErrorKind> {
         self.arithmetic(
             val,
-            &|x: i64, y: i64| x + y,
+            &|x: i64, y: i64| x.checked_add(y),
             &|x: f64, y: f64| x + y,
-            &|x: u32, y: u32| x + y,
+            &|x: u32, y: u32| x.checked_add(y),
         )
     }
 
     #[inline (always)]
-    pub fn sub(&mut self, val: Value) {
+    pub fn sub(&mut self, val: Value) -> Result<(), ErrorKind> {
         self.arithmetic(
             val,
-            &|x: i64, y: i64| x - y,
+            &|x: i64, y: i64| x.checked_sub(y),
             &|x: f64, y: f64| x - y,
-            &|x: u32, y: u32| x - y,
+            &|x: u32, y: u32| x.checked_sub(y),
         )
     }
 
     #[inline (always)]
-    pub fn mul(&mut self, val: Value) {
+    pub fn mul(&mut self, val: Value) -> Result<(), ErrorKind> {
         self.arithmetic(
             val,
-            &|x: i64, y: i64| x * y,
+            &|x: i64, y: i64| x.checked_mul(y),
             &|x: f64, y: f64| x * y,
-            &|x: u32, y: u32| x * y,
+            &|x: u32, y: u32| x.checked_mul(y),
         )
     }
 
     #[inline (always)]
-    pub fn div(&mut self, val: Value) {
+    pub fn div(&mut self, val: Value) -> Result<(), ErrorKind> {
+        self.check_numeric(&val)?;
+        if val.is_zero() {
+            return Err(ErrorKind::DivisionByZero)
+        }
         self.arithmetic(
             val,
-            &|x: i64, y: i64| x / y,
+            &|x: i64, y: i64| x.checked_div(y),
             &|x: f64, y: f64| x / y,
-            &|x: u32, y: u32| x / y,
+            &|x: u32, y: u32| x.checked_div(y),
         )
     }
 
     #[inline (always)]
-    pub fn rem(&mut self, val: Value) {
+    pub fn rem(&mut self, val: Value) -> Result<(), ErrorKind> {
+        self.check_numeric(&val)?;
+        if val.is_zero() {
+            return Err(ErrorKind::DivisionByZero)
+        }
         self.arithmetic(
             val,
-            &|x: i64, y: i64| x % y,
+            &|x: i64, y: i64| x.checked_rem(y),
             &|x: f64, y: f64| x % y,
-            &|x: u32, y: u32| x % y,
+            &|x: u32, y: u32| x.checked_rem(y),
         )
     }
 
-    pub fn and(&mut self, val: Value) {
+    pub fn and(&mut self, val: Value) -> Result<(), ErrorKind> {
         match self {
-            Value::Int(s) => *s = *s & val.try_into_int(),
-            Value::Bool(s) => *s = *s & val.try_into_bool(),
-            Value::Char(s) => *s = *s & val.try_into_char(),
-            _ => panic!("{}", LOGICAL_WRONG_TYPE)
+            Value::Int(s) => *s &= val.try_into_int()?,
+            Value::Bool(s) => *s &= val.try_into_bool()?,
+            Value::Char(s) => *s &= val.try_into_char()?,
+            _ => return Err(ErrorKind::TypeMismatch(LOGICAL_WRONG_TYPE))
         }
+        Ok(())
     }
 
-    pub fn or(&mut self, val: Value) {
+    pub fn or(&mut self, val: Value) -> Result<(), ErrorKind> {
         match self {
-            Value::Int(s) => *s = *s | val.try_into_int(),
-            Value::Bool(s) => *s = *s | val.try_into_bool(),
-            Value::Char(s) => *s = *s | val.try_into_char(),
-            _ => panic!("{}", LOGICAL_WRONG_TYPE)
+            Value::Int(s) => *s |= val.try_into_int()?,
+            Value::Bool(s) => *s |= val.try_into_bool()?,
+            Value::Char(s) => *s |= val.try_into_char()?,
+            _ => return Err(ErrorKind::TypeMismatch(LOGICAL_WRONG_TYPE))
         }
+        Ok(())
     }
 
-    pub fn xor(&mut self, val: Value) {
+    pub fn xor(&mut self, val: Value) -> Result<(), ErrorKind> {
         match self {
-            Value::Int(s) => *s = *s ^ val.try_into_int(),
-            Value::Bool(s) => *s = *s ^ val.try_into_bool(),
-            Value::Char(s) => *s = *s ^ val.try_into_char(),
-            _ => panic!("{}", LOGICAL_WRONG_TYPE)
+            Value::Int(s) => *s ^= val.try_into_int()?,
+            Value::Bool(s) => *s ^= val.try_into_bool()?,
+            Value::Char(s) => *s ^= val.try_into_char()?,
+            _ => return Err(ErrorKind::TypeMismatch(LOGICAL_WRONG_TYPE))
         }
+        Ok(())
     }
 
-    pub fn not(&mut self) {
+    pub fn not(&mut self) -> Result<(), ErrorKind> {
         match self {
             Value::Int(s) => *s = ! *s,
             Value::Bool(s) => *s = ! *s,
             Value::Char(s) => *s = ! *s,
-            _ => panic!("{}", LOGICAL_WRONG_TYPE)
+            _ => return Err(ErrorKind::TypeMismatch(LOGICAL_WRONG_TYPE))
         }
+        Ok(())
     }
 
-    pub fn gt(&self, val: Value) -> bool {
+    pub fn gt(&self, val: Value) -> Result<bool, ErrorKind> {
         match self {
-            Value::Int(s) => *s > val.try_into_int(),
-            Value::Float(s) => *s > val.try_into_float(),
-            Value::Char(s) => *s > val.try_into_char(),
-            _ => panic!("{}", COMPARE_WRONG_TYPE)
+            Value::Int(s) => Ok(*s > val.try_into_int()?),
+            Value::Float(s) => Ok(*s > val.try_into_float()?),
+            Value::Char(s) => Ok(*s > val.try_into_char()?),
+            Value::Ref(s) => Ok(s.as_str().ok_or(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE))? > val.try_as_str()?),
+            _ => Err(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE))
         }
     }
 
-    pub fn lt(&self, val: Value) -> bool {
+    pub fn lt(&self, val: Value) -> Result<bool, ErrorKind> {
         match self {
-            Value::Int(s) => *s < val.try_into_int(),
-            Value::Float(s) => *s < val.try_into_float(),
-            Value::Char(s) => *s < val.try_into_char(),
-            _ => panic!("{}", COMPARE_WRONG_TYPE)
+            Value::Int(s) => Ok(*s < val.try_into_int()?),
+            Value::Float(s) => Ok(*s < val.try_into_float()?),
+            Value::Char(s) => Ok(*s < val.try_into_char()?),
+            Value::Ref(s) => Ok(s.as_str().ok_or(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE))? < val.try_as_str()?),
+            _ => Err(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE))
         }
     }
 
-    pub fn eq(&self, val: Value) -> bool {
+    // loose equality, `Seq` uses PartialEq instead
+    pub fn equals(&self, val: Value) -> Result<bool, ErrorKind> {
         match self {
-            Value::Int(s) => *s == val.try_into_int(),
-            Value::Float(s) => *s == val.try_into_float(),
-            Value::Char(s) => *s == val.try_into_char(),
-            _ => panic!("{}", COMPARE_WRONG_TYPE)
+            Value::Int(s) => Ok(*s == val.try_into_int()?),
+            Value::Float(s) => Ok(*s == val.try_into_float()?),
+            Value::Char(s) => Ok(*s == val.try_into_char()?),
+            Value::Ref(s) => Ok(s.as_str().ok_or(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE))? == val.try_as_str()?),
+            _ => Err(ErrorKind::TypeMismatch(COMPARE_WRONG_TYPE))
         }
     }
 }
@@ -0,0 +1,313 @@
+use axolotl::asm::{self, AsmError};
+use axolotl::disasm::{self, DisasmError};
+use axolotl::vm::bytecode::ByteCode::{self, *};
+use axolotl::vm::value::Value;
+use axolotl::vm::object::ObjType;
+use axolotl::builtin::linkedlist::List;
+use axolotl::frontend::parser;
+use axolotl::frontend::gen::GenEnv;
+use axolotl::frontend::token::Span;
+use std::collections::BTreeSet;
+use std::fs;
+use std::rc::Rc;
+
+mod common;
+use common::TempDir;
+
+#[test]
+fn test_round_trip() {
+    let mut files = 0;
+    for entry in fs::read_dir("asm").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "asm") {
+            continue
+        }
+        files += 1;
+        let program = asm::compile_to_enum(fs::read_to_string(&path).unwrap()).unwrap();
+        let text = disasm::disassemble(&program).unwrap();
+        assert_eq!(program, asm::compile_to_enum(text).unwrap(), "{}", path.display());
+    }
+    assert!(files > 0);
+}
+
+#[test]
+fn test_quoted() {
+    let source = "(list 'x '(1 \"a;; b\" (\\space nil) if) '())";
+    let program = GenEnv::new().generate_with_halt(&parser::parse(source).unwrap()).unwrap();
+    let text = disasm::disassemble(&program).unwrap();
+    assert!(text.starts_with("push 'x\npush '(1 \"a;\\u{3b} b\" (\\space nil) if)\npush '()\n"), "{}", text);
+    assert_eq!(program, asm::compile_to_enum(text).unwrap());
+
+    assert!(matches!(&asm::compile_to_enum("push '(a\npush 'a b".to_string()).unwrap_err()[..],
+                     [AsmError::InvalidArgument(_, _), AsmError::InvalidArgument(_, _)]));
+}
+
+#[test]
+fn test_lables() {
+    let program = vec![
+        Push(Value::Int(3)),
+        Dec,
+        Dup,
+        PopJmpIf(1),
+        Jmp(5),  // past the end, stays a number
+    ];
+    let text = disasm::disassemble(&program).unwrap();
+    assert_eq!("push 3\n\
+                --                      <- L1\n\
+                dup\n\
+                pop_jmp_if L1\n\
+                jmp 5\n", text);
+    assert_eq!(program, asm::compile_to_enum(text).unwrap());
+}
+
+#[test]
+fn test_no_syntax() {
+    // the lisp has no negative int literal
+    let list = Value::Ref(Rc::new(ObjType::Cons(List::new().prepend(Value::Int(-1)))));
+    let program = vec![Push(Value::Int(1)), Push(list), HALT];
+    assert!(matches!(disasm::disassemble(&program), Err(DisasmError::NoSyntax(1, Push(_)))));
+    assert!(matches!(disasm::disassemble(&[Push(Value::Float(1e300))]), Err(DisasmError::NoSyntax(0, _))));
+}
+
+#[test]
+fn test_errors() {
+    let source = "\
+push 1
+;; a comment, and a blank line
+
+call mian
+pop <- main
+pusj 2
+get x
+push 1 <- main
+";
+    let errors = asm::compile_to_enum(source.to_string()).unwrap_err();
+    assert_eq!(4, errors.len());
+    let spans: Vec<_> = errors.iter().map(|err| { let s = err.span(); (s.line, s.col, s.len) }).collect();
+
+    assert!(matches!(&errors[0], AsmError::DuplicateLable(lable, _) if lable == "main"));
+    assert_eq!((8, 11, 4), spans[0]);
+    assert!(matches!(&errors[1], AsmError::UndefinedLable(lable, Some(close), _) if lable == "mian" && close == "main"));
+    assert_eq!("undefined label `mian`, did you mean `main`?", errors[1].to_string());
+    assert_eq!((4, 6, 4), spans[1]);
+    assert!(matches!(&errors[2], AsmError::UnknownInstruction(name, Some(close), _) if name == "pusj" && close == "push"));
+    assert_eq!((6, 1, 4), spans[2]);
+    assert!(matches!(&errors[3], AsmError::InvalidArgument(_, _)));
+    assert_eq!((7, 5, 1), spans[3]);
+
+    assert!(matches!(&asm::compile_to_enum("push 99999999999999999999".to_string()).unwrap_err()[..],
+                     [AsmError::InvalidArgument(_, _)]));
+    assert!(matches!(&asm::compile_to_enum("qwerty".to_string()).unwrap_err()[..],
+                     [AsmError::UnknownInstruction(_, None, _)]));
+}
+
+// which variant it is. there is no `_`, so a new ByteCode doesn't compile
+// until it gets a number here, and a sample in every_instruction
+// a new ByteCode needs an arm in variant, and this to be one more
+const VARIANTS: usize = 62;
+
+fn variant(code: &ByteCode) -> usize {
+    match code {
+        HALT => 0, Push(_) => 1, Pop => 2, Swap => 3, Dup => 4, Copy(_) => 5,
+        Get(_) => 6, Set(_) => 7, Arg(_) => 8, LoadLocal(_) => 9, StoreLocal(_) => 10,
+        Jmp(_) => 11, PopJmpIf(_) => 12, PopJmpIfNot(_) => 13, Ret => 14, Call(_) => 15,
+        Greater => 16, GreaterEq => 17, Less => 18, LessEq => 19, Eq => 20, Neq => 21, Seq => 22, Sneq => 23,
+        Add => 24, Sub => 25, Mul => 26, Div => 27, Rem => 28, Inc => 29, Dec => 30,
+        And => 31, Or => 32, Not => 33, Xor => 34,
+        Print => 35, PrintLn => 36, ReadLine => 37, Exit => 38,
+        CollectList(_) => 39, CollectCharList(_) => 40, Concat => 41, Len => 42, CharAt => 43,
+        Substr => 44, StrToList => 45, ListToStr => 46, Car => 47, Cdr => 48, Cons => 49,
+        IsNil => 50, IsList => 51, NoMatch => 52, CallTopFn(_) => 53, TailCallTopFn(_) => 54,
+        CallNative(_) => 55, MakeClosure(_) => 56, LoadCapture(_) => 57, LoadRec(_) => 58,
+        MakeCell => 59, CellGet => 60, CellSet => 61,
+    }
+}
+
+fn every_instruction() -> Vec<ByteCode> {
+    let str = |s: &str| Value::Ref(Rc::new(ObjType::Str(s.to_string())));
+    let sym = |s: &str| Value::Ref(Rc::new(ObjType::Sym(s.to_string())));
+    let list = |values: Vec<Value>| Value::Ref(Rc::new(ObjType::Cons(values.into_iter().rev().fold(List::new(), |list, value| list.prepend(value)))));
+    let func = Value::Ref(Rc::new(ObjType::Func(1, vec![Arg(0), Dup, PopJmpIf(4), Pop, Ret])));
+    vec![
+        HALT, Push(Value::Int(-1)), Push(Value::Float(1.5)), Push(Value::Char('a' as u32)),
+        Push(str("hi")), Push(str("")), Push(Value::Nil), Push(Value::Bool(true)), Push(Value::Bool(false)),
+        Push(func), Push(sym("a")), Push(list(vec![Value::Int(1), sym("def"), Value::Char(' ' as u32), str("x;; y")])),
+        Pop, Swap, Dup, Copy(2),
+        Get(0), Set(1), Arg(2), LoadLocal(3), StoreLocal(4),
+        Jmp(1), PopJmpIf(2), PopJmpIfNot(3), Ret, Call(4),
+        Greater, GreaterEq, Less, LessEq, Eq, Neq, Seq, Sneq,
+        Add, Sub, Mul, Div, Rem, Inc, Dec, And, Or, Not, Xor,
+        Print, PrintLn, ReadLine, Exit,
+        CollectList(2), CollectCharList(3), Concat, Len, CharAt,
+        Substr, StrToList, ListToStr, Car, Cdr, Cons,
+        IsNil, IsList, NoMatch, CallTopFn(2), TailCallTopFn(1),
+        CallNative(0), MakeClosure(1), LoadCapture(0), LoadRec(1),
+        MakeCell, CellGet, CellSet,
+    ]
+}
+
+#[test]
+fn test_every_instruction() {
+    let program = every_instruction();
+    let variants: BTreeSet<usize> = program.iter().map(variant).collect();
+    assert_eq!((0..VARIANTS).collect::<BTreeSet<_>>(), variants);
+
+    let text = disasm::disassemble(&program).unwrap();
+    assert_eq!(program, asm::compile_to_enum(text).unwrap());
+}
+
+#[test]
+fn test_push_fn() {
+    let source = "\
+push fn 2
+    arg 0                   ;; the body has lables of its own
+    arg 1 <- again
+    >=
+    pop_jmp_if again
+    push fn 0
+        push nil
+        ret
+    end
+    call_top_fn 0
+    ret
+end
+copy 1
+copy -1
+call_top_fn 2
+";
+    let inner = Value::Ref(Rc::new(ObjType::Func(0, vec![Push(Value::Nil), Ret])));
+    let body = vec![Arg(0), Arg(1), GreaterEq, PopJmpIf(1), Push(inner), CallTopFn(0), Ret];
+    let program = vec![Push(Value::Ref(Rc::new(ObjType::Func(2, body)))), Copy(1), Copy(1), CallTopFn(2)];
+    assert_eq!(program, asm::compile_to_enum(source.to_string()).unwrap());
+
+    assert!(matches!(&asm::compile_to_enum("push fn 1\nret".to_string()).unwrap_err()[..],
+                     [AsmError::UnclosedFn(_)]));
+    assert!(matches!(&asm::compile_to_enum("ret\nend".to_string()).unwrap_err()[..],
+                     [AsmError::UnexpectedEnd(_)]));
+}
+
+#[test]
+fn test_lisp_round_trip() {
+    let source = r#"
+(def fact (fn (n) (if (< n 2) 1 (* n (fact (- n 1))))))
+(def add (fn (x) (fn (y) (+ x y))))
+(let ((s "axolotl"))
+  (display (string-append s (substring s 0 2)))
+  ((add 1) (fact 5)))
+"#;
+    let forms = parser::parse_program(source).unwrap();
+    let program = GenEnv::new().generate_program(&forms).unwrap();
+    let text = disasm::disassemble(&program).unwrap();
+    assert_eq!(program, asm::compile_to_enum(text).unwrap());
+}
+
+#[test]
+fn test_escapes() {
+    let str = |s: &str| Push(Value::Ref(Rc::new(ObjType::Str(s.to_string()))));
+    let source = r#"
+push '\n'
+push '''
+push '\u{3bb}'
+push ' '
+push "say \"hi\"\n\t\\ \u{1F980}"
+push "a;\u{3b} b \u{3c}- c"
+"#;
+    let program = vec![
+        Push(Value::Char('\n' as u32)), Push(Value::Char('\'' as u32)), Push(Value::Char('λ' as u32)),
+        Push(Value::Char(' ' as u32)), str("say \"hi\"\n\t\\ \u{1F980}"), str("a;; b <- c"),
+    ];
+    assert_eq!(program, asm::compile_to_enum(source.to_string()).unwrap());
+    let text = disasm::disassemble(&program).unwrap();
+    assert_eq!(program, asm::compile_to_enum(text).unwrap());
+
+    let errors = asm::compile_to_enum("push \"\\q\"\npush 'ab'".to_string()).unwrap_err();
+    assert!(matches!(&errors[..], [AsmError::InvalidLiteral(_, _), AsmError::InvalidLiteral(_, _)]));
+    assert_eq!("unknown escape `\\q`", errors[0].to_string());
+}
+
+#[test]
+fn test_define_and_macro() {
+    let source = "\
+%define N 3
+%define M N                 ;; defines in the value are replaced too
+%macro count_down from
+    push from
+    dup <- %%loop
+    println
+    --
+    dup
+    push 0
+    >
+    pop_jmp_if %%loop
+%endmacro
+
+count_down N
+count_down M <- again       ;; the lable is on the first line of the expansion
+push \"N\"                  ;; not in strings
+HALT
+";
+    let once = |start: usize| {
+        let lable = start + 1;
+        vec![Push(Value::Int(3)), Dup, PrintLn, Dec, Dup, Push(Value::Int(0)), Greater, PopJmpIf(lable)]
+    };
+    let mut program = once(0);
+    program.extend(once(8));
+    program.extend([Push(Value::Ref(Rc::new(ObjType::Str("N".to_string())))), HALT]);
+    assert_eq!(program, asm::compile_to_enum(source.to_string()).unwrap());
+
+    let errors = asm::compile_to_enum("\
+%define N 1
+%macro two a b
+    push a
+    pusj b
+%endmacro
+%define N 2
+two 1
+two 1 2
+%defin X 1
+%macro loop
+    loop
+%endmacro
+loop
+%macro open
+".to_string()).unwrap_err();
+    let lines: Vec<_> = errors.iter().map(|err| err.span().line).collect();
+    assert!(matches!(&errors[0], AsmError::AlreadyDefined(name, _) if name == "N"));
+    assert!(matches!(&errors[1], AsmError::WrongNumberOfArgument(2, 1, _)));
+    assert!(matches!(&errors[2], AsmError::UnknownInstruction(name, Some(close), _) if name == "%defin" && close == "%define"));
+    assert!(matches!(&errors[3], AsmError::TooDeep(name, _) if name == "loop"));
+    assert!(matches!(&errors[4], AsmError::UnclosedMacro(_)));
+    // found when assembling, reported at where the macro is used
+    assert!(matches!(&errors[5], AsmError::UnknownInstruction(name, Some(close), _) if name == "pusj" && close == "push"));
+    assert_eq!(vec![6, 7, 9, 13, 14, 8], lines);
+}
+
+#[test]
+fn test_include() {
+    let dir = TempDir::new("asm-include", &[
+        ("main.asm", "%include \"lib/consts.asm\"\npush ANSWER\ncall twice\nHALT\n%include \"lib/twice.asm\"\n"),
+        ("lib/consts.asm", "%define ANSWER 42\n"),
+        ("lib/twice.asm", "dup <- twice\n+\nret\n"),
+        ("a.asm", "push 1\n%include \"b.asm\"\n"),
+        ("b.asm", "\n\n%include \"a.asm\"\n"),
+        ("broken.asm", "push 1\n%include \"lib/typo.asm\"\n%include \"nothing.asm\"\n"),
+        ("lib/typo.asm", "\npusj 1\n"),
+    ]);
+    let compile = |file: &str| {
+        let path = dir.join(file);
+        asm::compile_file(fs::read_to_string(&path).unwrap(), &path)
+    };
+
+    let program = vec![Push(Value::Int(42)), Call(3), HALT, Dup, Add, Ret];
+    assert_eq!(program, compile("main.asm").unwrap());
+
+    let errors = compile("a.asm").unwrap_err();
+    assert!(matches!(&errors[..], [AsmError::InFile(path, err, span)]
+                     if path == "b.asm" && span.line == 2 && matches!(**err, AsmError::CyclicInclude(_, Span { line: 3, .. }))));
+    assert!(errors[0].to_string().contains("a.asm -> "));
+
+    let errors = compile("broken.asm").unwrap_err();
+    assert!(matches!(&errors[..], [AsmError::IncludeNotFound(path, _), AsmError::InFile(file, err, span)]
+                     if path == "nothing.asm" && file == "lib/typo.asm" && span.line == 2
+                         && matches!(**err, AsmError::UnknownInstruction(_, _, Span { line: 2, col: 1, len: 4 }))));
+}
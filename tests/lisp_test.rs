@@ -0,0 +1,689 @@
+use axolotl::frontend::parser;
+use axolotl::frontend::diagnostic;
+use axolotl::frontend::repl::Session;
+use axolotl::frontend::gen::{GenEnv, CodeGenError};
+use axolotl::vm::machine::VM;
+use axolotl::vm::value::Value;
+use axolotl::vm::object::ObjType;
+use axolotl::vm::bytecode::ByteCode;
+use axolotl::vm::error::ErrorKind;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io::Cursor;
+
+mod common;
+use common::{Output, TempDir};
+
+// run every line like the repl does, and return the last result
+fn eval(lines: &[&str]) -> Value {
+    let mut env = GenEnv::new();
+    let mut vm = VM::default();
+    for line in lines {
+        let parsed = parser::parse(line).unwrap();
+        let code = env.generate_with_halt(&parsed).unwrap();
+        vm.run(&code).unwrap();
+        vm.reset_pc();
+    }
+    vm.stack.pop().unwrap()
+}
+
+#[test]
+fn test_function() {
+    assert_eq!(Value::Int(3), eval(&[
+        "(def add (fn (a b) (+ a b)))",
+        "(add 1 2)",
+    ]));
+}
+
+#[test]
+fn test_closure() {
+    assert_eq!(Value::Int(15), eval(&[
+        "(def make-adder (fn (n) (fn (x) (+ x n))))",
+        "(def add5 (make-adder 5))",
+        "(add5 10)",
+    ]));
+    assert_eq!(Value::Int(7), eval(&[
+        "(def make-adder (fn (n) (fn (x) (+ x n))))",
+        "((make-adder 3) 4)",
+    ]));
+}
+
+#[test]
+fn test_nested_closure() {
+    // c captures b directly and a through the middle lambda
+    assert_eq!(Value::Int(6), eval(&[
+        "(def f (fn (a) (fn (b) (fn (c) (+ a (+ b c))))))",
+        "(((f 1) 2) 3)",
+    ]));
+}
+
+fn function_body(line: &str) -> Vec<ByteCode> {
+    let mut env = GenEnv::new();
+    let code = env.generate_with_halt(&parser::parse(line).unwrap()).unwrap();
+    match &code[0] {
+        ByteCode::Push(Value::Ref(rf)) => rf.body().unwrap().to_vec(),
+        _ => panic!("not a function"),
+    }
+}
+
+#[test]
+fn test_tail_call() {
+    let body = function_body("(fn (f n) (f n))");
+    assert_eq!(Some(&ByteCode::TailCallTopFn(1)), body.last());
+
+    let body = function_body("(fn (f n) (+ 1 (f n)))");
+    assert!(body.contains(&ByteCode::CallTopFn(1)));
+    assert!(! body.iter().any(|b| matches!(b, ByteCode::TailCallTopFn(_))));
+}
+
+#[test]
+fn test_native() {
+    let logged = Rc::new(RefCell::new(vec![]));
+    let mut vm = VM::default();
+    let log = logged.clone();
+    vm.register_native("log", 1, move |_vm, args| {
+        log.borrow_mut().push(args[0].clone());
+        Ok(Value::Nil)
+    });
+    let mut env = GenEnv::new();
+    env.bind_natives(&vm);
+
+    let code = env.generate_with_halt(&parser::parse("(log (+ 1 2))").unwrap()).unwrap();
+    vm.run(&code).unwrap();
+    assert_eq!(vec![Value::Int(3)], *logged.borrow());
+    assert_eq!(Some(&Value::Nil), vm.stack.last());
+
+    assert!(matches!(
+        env.generate_with_halt(&parser::parse("(log 1 2)").unwrap()),
+        Err(CodeGenError::WrongNumberOfArgument(1, 2, _))
+    ));
+}
+
+#[test]
+fn test_io() {
+    let output = Output::default();
+    let mut vm = VM::default()
+        .set_output(output.clone())
+        .set_input(Cursor::new("axolotl\n"));
+    let mut env = GenEnv::new();
+    for line in [
+        "(def name (read-line))",
+        "(display 1)",
+        "(newline)",
+        "(displayln name)",
+        "(exit 2)",
+    ] {
+        let code = env.generate_with_halt(&parser::parse(line).unwrap()).unwrap();
+        vm.run(&code).unwrap();
+        vm.reset_pc();
+    }
+    assert_eq!("1\naxolotl\n", output.text());
+    assert_eq!(Some(2), vm.exit_code);
+}
+
+#[test]
+fn test_exit_in_call() {
+    let source = "(def g (fn (n) (if (== n 0) (exit 4) (+ 1 (g (- n 1))))))
+                  (def f (fn (a) (let ((b (+ a 1))) (g b))))
+                  (f 2)";
+    let forms = parser::parse_program(source).unwrap();
+    let program = GenEnv::new().generate_program(&forms).unwrap();
+    let mut vm = VM::default();
+    vm.run(&program).unwrap();
+    assert_eq!(Some(4), vm.exit_code);
+    assert!(vm.frames.is_empty());
+    assert!(vm.locals.is_empty());
+}
+
+#[test]
+fn test_program() {
+    let source = r#"
+(def square (fn (x) (* x x)))
+(def n 4)
+(square n)
+"#;
+    let forms = parser::parse_program(source).unwrap();
+    assert_eq!(3, forms.len());
+    let program = GenEnv::new().generate_program(&forms).unwrap();
+    let mut vm = VM::default();
+    vm.run(&program).unwrap();
+    assert_eq!([Value::Int(16)], vm.stack[..]);
+}
+
+#[test]
+fn test_error_span() {
+    let source = "(def x 1)\n(+ x\n   y)";
+    let forms = parser::parse_program(source).unwrap();
+    let err = GenEnv::new().generate_program(&forms).unwrap_err();
+    assert!(matches!(&err, CodeGenError::SymbolNotFound(sym, _) if sym == "y"));
+    assert_eq!((3, 4), (err.span().line, err.span().col));
+    assert_eq!("[CODEGEN]: symbol `y` not found\n --> test.lisp:3:4\n  |\n3 |    y)\n  |    ^",
+               diagnostic::render(source, "test.lisp", "CODEGEN", &err.to_string(), Some(err.span())));
+}
+
+// the whole stack after running a program, so leftovers show up
+fn run_program(source: &str) -> Vec<Value> {
+    let forms = parser::parse_program(source).unwrap();
+    let program = GenEnv::new().generate_program(&forms).unwrap();
+    let mut vm = VM::default();
+    vm.run(&program).unwrap();
+    vm.stack
+}
+
+#[test]
+fn test_if() {
+    assert_eq!([Value::Int(1)], run_program("(if true 1 2)")[..]);
+    assert_eq!([Value::Int(2)], run_program("(if false 1 2)")[..]);
+    assert_eq!([Value::Int(1)], run_program("(if 0 1 2)")[..]);  // only false is false
+    assert_eq!([Value::Nil], run_program("(if false 1)")[..]);
+    // nested branches are moved, their jumps must follow
+    assert_eq!([Value::Int(3)], run_program(r#"
+(def x 3)
+(if (if false true false) (+ x 1) (if true (+ x 0) 5))
+"#)[..]);
+    assert_eq!([Value::Int(6)], run_program("(def x 3) (* x (if x 2 1))")[..]);
+
+    let gen_err = |source| GenEnv::new().generate_program(&parser::parse_program(source).unwrap()).unwrap_err();
+    assert!(matches!(gen_err("(if true)"), CodeGenError::ArgumentOutOfRange(2, 3, 1, _)));
+    assert_eq!("expected 2 or 3 arguments, got 1", gen_err("(if true)").to_string());
+    assert_eq!("expected 2 or 3 arguments, got 4", gen_err("(if true 1 2 3)").to_string());
+}
+
+#[test]
+fn test_if_in_function() {
+    assert_eq!([Value::Int(7)], run_program(r#"
+(def f (fn (again) (if again (f false) 7)))
+(f true)
+"#)[..]);
+    let body = function_body("(fn (f b) (if b (f b) 0))");
+    assert!(body.iter().any(|b| matches!(b, ByteCode::TailCallTopFn(_))));
+}
+
+#[test]
+fn test_when_unless() {
+    let output = Output::default();
+    let forms = parser::parse_program(r#"
+(when true (display 1) (display 2) 3)
+(unless true (display 4))
+(unless false (display 5) 6)
+(when false)
+"#).unwrap();
+    let program = GenEnv::new().generate_program(&forms).unwrap();
+    let mut vm = VM::default().set_output(output.clone());
+    vm.run(&program).unwrap();
+    assert_eq!("125", output.text());
+    assert_eq!([Value::Nil], vm.stack[..]);
+
+    assert_eq!([Value::Int(6)], run_program("(unless false (display 5) 6)")[..]);
+    assert_eq!([Value::Nil], run_program("(unless true 1)")[..]);
+}
+
+#[test]
+fn test_cond() {
+    let cond = |x: &str, y: &str| run_program(&format!(r#"
+(def f (fn (x y)
+  (cond (x 1)
+        (y 2 3)
+        (else 4))))
+(f {} {})
+"#, x, y));
+    assert_eq!([Value::Int(1)], cond("true", "true")[..]);
+    assert_eq!([Value::Int(3)], cond("false", "true")[..]);
+    assert_eq!([Value::Int(4)], cond("false", "false")[..]);
+
+    assert_eq!([Value::Nil], run_program("(cond (false 1))")[..]);
+    assert!(matches!(
+        GenEnv::new().generate_program(&parser::parse_program("(cond (else 1) (true 2))").unwrap()),
+        Err(CodeGenError::ArgTypeError(_))
+    ));
+}
+
+#[test]
+fn test_compare() {
+    assert_eq!([Value::Bool(true)], run_program("(< 1 2)")[..]);
+    assert_eq!([Value::Bool(false)], run_program("(>= 1 2)")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(== 2 (+ 1 1))")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(!= 1 2)")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(< 1 2 3 4)")[..]);
+    assert_eq!([Value::Bool(false)], run_program("(< 1 3 2 4)")[..]);
+    assert_eq!([Value::Bool(false)], run_program("(> 3 2 2)")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(>= 3 2 2)")[..]);
+    assert!(matches!(
+        GenEnv::new().generate_program(&parser::parse_program("(< 1)").unwrap()),
+        Err(CodeGenError::WrongNumberOfArgument(2, 1, _))
+    ));
+}
+
+#[test]
+fn test_recursion_with_compare() {
+    assert_eq!([Value::Int(55)], run_program(r#"
+(def fib (fn (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))
+(fib 10)
+"#)[..]);
+    // tail calls reuse the frame, a million of them leave none behind
+    let forms = parser::parse_program(r#"
+(def count (fn (n acc) (if (== n 0) acc (count (- n 1) (+ acc 1)))))
+(count 1000000 0)
+"#).unwrap();
+    let mut vm = VM::default();
+    vm.run(&GenEnv::new().generate_program(&forms).unwrap()).unwrap();
+    assert_eq!([Value::Int(1000000)], vm.stack[..]);
+    assert!(vm.frames.is_empty());
+}
+
+#[test]
+fn test_variadic_arithmetic() {
+    assert_eq!([Value::Int(6)], run_program("(+ 1 2 3)")[..]);
+    assert_eq!([Value::Int(4)], run_program("(- 10 1 2 3)")[..]);
+    assert_eq!([Value::Int(24)], run_program("(* 1 2 3 4)")[..]);
+    assert_eq!([Value::Int(5)], run_program("(/ 100 2 10)")[..]);
+    assert_eq!([Value::Int(1)], run_program("(% 7 3)")[..]);
+    assert_eq!([Value::Int(-5)], run_program("(- 5)")[..]);
+    assert_eq!([Value::Float(-1.5)], run_program("(- 1.5)")[..]);
+    assert_eq!([Value::Int(2)], run_program("(def x 3) (- (- x) (- 5))")[..]);
+
+    assert_eq!([Value::Int(0b100)], run_program("(& 7 6 12)")[..]);
+    assert_eq!([Value::Int(0b111)], run_program("(| 1 2 4)")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(^ true false false)")[..]);
+    assert_eq!([Value::Bool(false)], run_program("(! true)")[..]);
+    assert_eq!([Value::Int(4)], run_program("(++ 3)")[..]);
+    assert_eq!([Value::Int(2)], run_program("(-- 3)")[..]);
+
+    let gen_err = |source| GenEnv::new().generate_program(&parser::parse_program(source).unwrap()).unwrap_err();
+    assert!(matches!(gen_err("(* 2)"), CodeGenError::WrongNumberOfArgument(2, 1, _)));
+    assert!(matches!(gen_err("(% 7 3 2)"), CodeGenError::WrongNumberOfArgument(2, 3, _)));
+    assert!(matches!(gen_err("(+ 1 \"a\")"), CodeGenError::ArgTypeError(_)));
+}
+
+#[test]
+fn test_list() {
+    let sym = |s: &str| Value::Ref(Rc::new(ObjType::Sym(s.to_string())));
+    assert_eq!([Value::Int(1)], run_program("(car (list 1 2 3))")[..]);
+    assert_eq!([Value::Int(3)], run_program("(car (cdr (cdr '(1 2 3))))")[..]);
+    assert_eq!([sym("b")], run_program("(car (cdr '(a b)))")[..]);
+    assert_eq!([sym("+")], run_program("(car '(+ 1 2))")[..]);
+    assert_eq!([sym("x")], run_program("'x")[..]);
+    assert_eq!([sym("quote")], run_program("(car (car (cdr '(a 'b))))")[..]);
+    assert_eq!([Value::Int(0)], run_program("(car (cons 0 '(1 2)))")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(null? (cdr (cons 1 nil)))")[..]);
+    assert_eq!([Value::Bool(true)], run_program("(null? '())")[..]);
+    assert_eq!([Value::Bool(false)], run_program("(null? (list 1))")[..]);
+
+    assert_eq!([Value::Int(6)], run_program(r#"
+(def sum (fn (l) (if (null? l) 0 (+ (car l) (sum (cdr l))))))
+(sum '(1 2 3))
+"#)[..]);
+}
+
+#[test]
+fn test_string() {
+    let str = |s: &str| Value::Ref(Rc::new(ObjType::Str(s.to_string())));
+    assert_eq!([str("axolotl")], run_program(r#"(string-append "ax" "o" "lotl")"#)[..]);
+    assert_eq!([Value::Int(7)], run_program(r#"(string-length "axolotl")"#)[..]);
+    assert_eq!([Value::Char('o' as u32)], run_program(r#"(string-ref "axolotl" 2)"#)[..]);
+    assert_eq!([str("lot")], run_program(r#"(substring "axolotl" 3 6)"#)[..]);
+    assert_eq!([str("ok")], run_program(r#"(string \o \k)"#)[..]);
+    assert_eq!([Value::Char('x' as u32)], run_program(r#"(car (cdr (string->list "axolotl")))"#)[..]);
+    assert_eq!([str("xolotl")], run_program(r#"(list->string (cdr (string->list "axolotl")))"#)[..]);
+    assert_eq!([Value::Bool(true)], run_program(r#"(== "ab" (string-append "a" "b"))"#)[..]);
+    assert_eq!([Value::Bool(true)], run_program(r#"(=== "ab" (string \a \b))"#)[..]);
+    assert_eq!([Value::Bool(true)], run_program(r#"(< "a" "b" "c")"#)[..]);
+    assert!(matches!(
+        GenEnv::new().generate_program(&parser::parse_program("(string-append \"a\" 1)").unwrap()),
+        Err(CodeGenError::ArgTypeError(_))
+    ));
+}
+
+#[test]
+fn test_let() {
+    assert_eq!([Value::Int(3)], run_program("(let ((x 1) (y 2)) (+ x y))")[..]);
+    // the values of let are evaluated outside
+    assert_eq!([Value::Int(11)], run_program("(def x 10) (let ((x 1) (y x)) (+ x y))")[..]);
+    assert_eq!([Value::Int(2)], run_program("(let* ((x 1) (y (+ x 1))) y)")[..]);
+    assert_eq!([Value::Int(3)], run_program("(let ((x 1)) (let ((x 2) (y x)) (+ x y)))")[..]);
+    assert_eq!([Value::Nil], run_program("(let ())")[..]);
+
+    // locals of a frame, so recursion keeps them apart
+    assert_eq!([Value::Int(15)], run_program(r#"
+(def sum (fn (n) (if (== n 0) 0 (let ((m n)) (+ (sum (- n 1)) m)))))
+(sum 5)
+"#)[..]);
+    // and closures capture them
+    assert_eq!([Value::Int(7)], run_program(r#"
+(def f (let ((a 3) (b 4)) (fn () (+ a b))))
+(let ((c 100)) (f))
+"#)[..]);
+}
+
+#[test]
+fn test_letrec_and_begin() {
+    assert_eq!([Value::Bool(true)], run_program(r#"
+(letrec ((even? (fn (n) (if (== n 0) true (odd? (- n 1)))))
+         (odd? (fn (n) (if (== n 0) false (even? (- n 1))))))
+  (even? 10))
+"#)[..]);
+    assert_eq!([Value::Int(3)], run_program("(begin (def x 1) (def y 2) (+ x y))")[..]);
+    assert_eq!([Value::Int(120)], run_program(r#"
+(do (def fact (fn (n) (if (== n 0) 1 (* n (fact (- n 1))))))
+    (fact 5))
+"#)[..]);
+
+    // locals of the frame, so a recursive call doesn't overwrite them
+    assert_eq!([Value::Int(3)], run_program(r#"
+(def f (fn (n) (letrec ((g (fn () n))) (if (== n 0) (g) (+ (f (- n 1)) (g))))))
+(f 2)
+"#)[..]);
+    assert_eq!([Value::Int(3)], run_program(r#"
+(def f (fn (n) (begin (def m n) (if (== n 0) m (+ (f (- n 1)) m)))))
+(f 2)
+"#)[..]);
+    // functions of a letrec capture the frame and call each other
+    assert_eq!([Value::Int(10)], run_program(r#"
+(def count (fn (n step)
+  (letrec ((up (fn (i acc) (if (>= i n) acc (down (+ i step) (++ acc)))))
+           (down (fn (i acc) (up i acc))))
+    (up 0 0))))
+(+ (count 6 1) (count 8 2))
+"#)[..]);
+    assert_eq!([Value::Int(10)], run_program(r#"
+(def make (fn (k) (begin (def loop (fn (i) (if (== i 0) k (loop (- i 1))))) loop)))
+((make 10) 3)
+"#)[..]);
+    assert_eq!([Value::Int(5)], run_program("(letrec ((x 2) (f (fn () (+ x 3)))) (f))")[..]);
+}
+
+#[test]
+fn test_block_scope() {
+    // names of a block are gone after it, also between repl entries
+    let mut env = GenEnv::new();
+    for line in ["(let ((x 1)) x)", "(begin (def y 1) y)", "(letrec ((z 1)) z)"] {
+        env.generate_with_halt(&parser::parse(line).unwrap()).unwrap();
+    }
+    for name in ["x", "y", "z"] {
+        assert!(matches!(
+            env.generate_with_halt(&parser::parse(name).unwrap()),
+            Err(CodeGenError::SymbolNotFound(sym, _)) if sym == name
+        ));
+    }
+    assert!(env.sym[0].is_empty());
+
+    // slots of a finished block are used again
+    let mut env = GenEnv::new();
+    let code = env.generate_with_halt(&parser::parse("(+ (let ((a 1)) a) (let ((b 2)) b))").unwrap()).unwrap();
+    assert!(! code.contains(&ByteCode::StoreLocal(1)));
+    assert!(matches!(
+        env.generate_with_halt(&parser::parse("(let ((1 2)) 3)").unwrap()),
+        Err(CodeGenError::ArgTypeError(_))
+    ));
+}
+
+#[test]
+fn test_set() {
+    assert_eq!([Value::Int(2)], run_program("(def x 1) (set x 2) x")[..]);
+    assert_eq!([Value::Int(5)], run_program("(def x 1) (set x 5)")[..]);  // returns the value
+    assert_eq!([Value::Int(3)], run_program("(let ((x 1)) (set x (+ x 2)) x)")[..]);
+    assert_eq!([Value::Int(10)], run_program("(def f (fn (n) (begin (set n (* n 2)) n))) (f 5)")[..]);
+    // a global changed inside a function
+    assert_eq!([Value::Int(3)], run_program(r#"
+(def counter 0)
+(def tick (fn () (set counter (++ counter))))
+(tick) (tick) (tick)
+counter
+"#)[..]);
+    // a closure sees a captured variable set after it was made
+    assert_eq!([Value::Int(5)], run_program("(let ((n 0)) (def get (fn () n)) (set n 5) (get))")[..]);
+    assert_eq!([Value::Int(3)], run_program(r#"
+(def make-counter (fn () (let ((c 0)) (fn () (set c (+ c 1))))))
+(def count (make-counter))
+(count) (count) (count)
+"#)[..]);
+    // two closures share an argument, the first call doesn't change another counter
+    assert_eq!([Value::Int(12)], run_program(r#"
+(def make (fn (n) (list (fn () (set n (+ n 1))) (fn () n))))
+(def a (make 10))
+(def b (make 0))
+((car a)) ((car a)) ((car b))
+((car (cdr a)))
+"#)[..]);
+    assert_eq!([Value::Int(2)], run_program(r#"
+(def total (fn (x y) (begin (def sum 0) (def add (fn (x) (set sum (+ sum x)))) (add x) (add y) sum)))
+(total 1 1)
+"#)[..]);
+
+    let gen_err = |source| GenEnv::new().generate_program(&parser::parse_program(source).unwrap()).unwrap_err();
+    assert!(matches!(gen_err("(set cuont 1)"), CodeGenError::SymbolNotFound(sym, _) if sym == "cuont"));
+    assert!(matches!(gen_err("(def x 1) (def x 2)"), CodeGenError::AlreadyDefined(sym, _) if sym == "x"));
+    assert!(matches!(gen_err("(begin (def y 1) (def y 2))"), CodeGenError::AlreadyDefined(sym, _) if sym == "y"));
+    assert!(matches!(gen_err("(letrec ((f (fn () (set f 1)))) f)"), CodeGenError::SetCapture(sym, _) if sym == "f"));
+    assert_eq!("symbol `x` is already defined, use set to change it", gen_err("(def x 1) (def x 2)").to_string());
+}
+
+#[test]
+fn test_match() {
+    let describe = |value: &str| run_program(&format!(r#"
+(def describe (fn (x)
+  (match x
+    (0 "zero")
+    ("hi" "greeting")
+    ('foo "foo")
+    (() "empty")
+    ((1 b) (string-append "one and " (string (car (string->list b)))))
+    ((h . t) when (== h 9) "starts with nine")
+    ((h . t) "a list")
+    (n when (> n 100) "big")
+    (_ "other"))))
+(describe {})
+"#, value));
+    let str = |s: &str| [Value::Ref(Rc::new(ObjType::Str(s.to_string())))];
+    assert_eq!(str("zero"), describe("0")[..]);
+    assert_eq!(str("greeting"), describe("\"hi\"")[..]);
+    assert_eq!(str("foo"), describe("'foo")[..]);
+    assert_eq!(str("empty"), describe("'()")[..]);
+    assert_eq!(str("one and x"), describe("(list 1 \"xyz\")")[..]);
+    assert_eq!(str("a list"), describe("'(1 2 3)")[..]);
+    assert_eq!(str("starts with nine"), describe("'(9)")[..]);
+    assert_eq!(str("big"), describe("101")[..]);
+    assert_eq!(str("other"), describe("7")[..]);
+
+    // bindings of the patterns
+    assert_eq!([Value::Int(6)], run_program(r#"
+(def sum (fn (l) (match l (() 0) ((h . t) (+ h (sum t))))))
+(sum '(1 2 3))
+"#)[..]);
+    assert_eq!([Value::Int(5)], run_program("(match '(1 (2 3)) ((a (b c)) (+ a (* b c) (- 2))))")[..]);
+    assert_eq!([Value::Int(3)], run_program("(match '(1 2 3) ((a b . rest) (+ a b)))")[..]);
+}
+
+#[test]
+fn test_no_match() {
+    let forms = parser::parse_program("(match 3 (1 true) (2 false))").unwrap();
+    let program = GenEnv::new().generate_program(&forms).unwrap();
+    let err = VM::default().run(&program).unwrap_err();
+    assert_eq!(ErrorKind::NoMatch("3".to_string()), err.kind);
+
+    assert!(matches!(
+        GenEnv::new().generate_program(&parser::parse_program("(match 1 ((a . b c) 1))").unwrap()),
+        Err(CodeGenError::ArgTypeError(_))
+    ));
+}
+
+// a fresh directory with the files for the module tests
+fn run_in(dir: &std::path::Path, source: &str) -> Result<Vec<Value>, CodeGenError> {
+    let forms = parser::parse_program(source).unwrap();
+    let program = GenEnv::new().set_dir(dir).generate_program(&forms)?;
+    let output = Output::default();
+    let mut vm = VM::default().set_output(output.clone());
+    vm.run(&program).unwrap();
+    Ok(vm.stack)
+}
+
+#[test]
+fn test_require() {
+    let dir = TempDir::new("require", &[
+        ("lib/math.lisp", r#"
+(require "square")
+(provide cube)
+(def helper (fn (x) (* x (square x))))
+(def cube (fn (x) (helper x)))
+(display "loaded")
+"#),
+        ("lib/square.lisp", "(provide square) (def square (fn (x) (* x x)))"),
+    ]);
+    assert_eq!(vec![Value::Int(27)], run_in(&dir, r#"
+(require "lib/math")
+(require "lib/math.lisp")
+(cube 3)
+"#).unwrap());
+
+    // only the provided names are seen
+    let err = run_in(&dir, "(require \"lib/math\") (helper 1)").unwrap_err();
+    assert!(matches!(err, CodeGenError::SymbolNotFound(sym, _) if sym == "helper"));
+    let err = run_in(&dir, "(require \"lib/math\") (square 1)").unwrap_err();
+    assert!(matches!(err, CodeGenError::SymbolNotFound(sym, _) if sym == "square"));
+    // and the module's own globals don't clash with the requirer's
+    assert_eq!(vec![Value::Int(9)], run_in(&dir, "(def helper 1) (require \"lib/math\") (+ helper (cube 2))").unwrap());
+
+    // compiled once
+    let forms = parser::parse_program("(require \"lib/math\") (require \"lib/math\")").unwrap();
+    let program = GenEnv::new().set_dir(&*dir).generate_program(&forms).unwrap();
+    let output = Output::default();
+    VM::default().set_output(output.clone()).run(&program).unwrap();
+    assert_eq!("loaded", output.text());
+}
+
+#[test]
+fn test_require_errors() {
+    let dir = TempDir::new("require-errors", &[
+        ("a.lisp", "(require \"b\")"),
+        ("b.lisp", "(require \"a\")"),
+        ("bad.lisp", "(provide nope)"),
+    ]);
+    let err = run_in(&dir, "(require \"a\")").unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("cyclic require"), "{}", message);
+    assert!(message.contains("a.lisp -> ") && message.contains("b.lisp -> "), "{}", message);
+
+    let err = run_in(&dir, "(require \"missing\")").unwrap_err();
+    assert!(matches!(err, CodeGenError::ModuleNotFound(name, _) if name == "missing"));
+    let err = run_in(&dir, "(require \"bad\")").unwrap_err();
+    assert!(err.to_string().contains("symbol `nope` not found"), "{}", err);
+    let err = run_in(&dir, "(fn () (require \"b\"))").unwrap_err();
+    assert!(matches!(err, CodeGenError::NotTopLevel(_)));
+}
+
+#[test]
+fn test_quasiquote() {
+    let ints = |ints: &[i64]| {
+        let code = format!("(list {})", ints.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(" "));
+        run_program(&code)
+    };
+    assert_eq!(ints(&[1, 2, 3]), run_program("(def x 2) `(1 ,x 3)"));
+    assert_eq!(ints(&[0, 1, 2, 3, 4]), run_program("(def l '(1 2 3)) `(0 ,@l 4)"));
+    assert_eq!(ints(&[1, 2, 1, 2]), run_program("(def l '(1 2)) `(,@l ,@l)"));
+    assert_eq!(ints(&[1, 2]), run_program("(def l '(1 2)) `(,@l ,@'())"));
+    assert_eq!(run_program("'(a (b 3))"), run_program("(def n 3) `(a (b ,n))"));
+    assert_eq!(run_program("'x"), run_program("`x"));
+}
+
+#[test]
+fn test_defmacro() {
+    assert_eq!([Value::Int(2)], run_program(r#"
+(defmacro my-unless (c . body) `(if ,c nil (begin ,@body)))
+(my-unless false (display 1) 2)
+"#)[..]);
+    assert_eq!([Value::Int(12)], run_program(r#"
+(defmacro -> (x . forms)
+  (if (null? forms)
+      x
+      (let ((f (car forms)))
+        `(-> (,(car f) ,x ,@(cdr f)) ,@(cdr forms)))))
+(-> 5 (+ 1) (* 2))
+"#)[..]);
+    // expanded in the body of a function, where the arguments are
+    assert_eq!([Value::Int(9)], run_program(r#"
+(defmacro square (x) `(* ,x ,x))
+(def f (fn (n) (square (+ n 1))))
+(f 2)
+"#)[..]);
+
+    let gen_err = |source| GenEnv::new().generate_program(&parser::parse_program(source).unwrap()).unwrap_err();
+    assert!(matches!(gen_err("(defmacro m (x) (car x)) (m 1)"), CodeGenError::MacroError(_, _)));
+    assert!(matches!(gen_err("(defmacro m () (fn () 1)) (m)"), CodeGenError::MacroError(_, _)));
+    assert!(matches!(gen_err("(defmacro m (x) x) (m)"), CodeGenError::WrongNumberOfArgument(1, 0, _)));
+    assert!(matches!(gen_err("(defmacro m () '(m)) (m)"), CodeGenError::MacroError(_, _)));
+    let err = gen_err("(defmacro m () 1) (defmacro m () 2)");
+    assert!(matches!(&err, CodeGenError::MacroAlreadyDefined(sym, _) if sym == "m"));
+    assert_eq!("macro `m` is already defined", err.to_string());
+}
+
+#[test]
+fn test_literal_escapes() {
+    let str = |s: &str| Value::Ref(Rc::new(ObjType::Str(s.to_string())));
+    assert_eq!([str("a\n\"b\"\t\\")], run_program(r#""a\n\"b\"\t\\""#)[..]);
+    assert_eq!([str("λ🦀")], run_program(r#""\u{3bb}\u{1f980}""#)[..]);
+    assert_eq!([Value::Int(3)], run_program(r#"(string-length "a\nb")"#)[..]);
+    assert_eq!([str(" \n\tA")], run_program(r"(string \space \newline \tab \u{41})")[..]);
+    assert_eq!([Value::Char('u' as u32)], run_program(r"\u")[..]);
+
+    let err = parser::parse_program(r#"(display "\q")"#).unwrap_err();
+    assert_eq!("unknown escape `\\q` in form 0", err.to_string());
+    assert_eq!(10, err.span().unwrap().col);
+    assert!(parser::parse_program(r"\spaces").is_err());
+}
+
+#[test]
+fn test_repl_recovers() {
+    let mut session = Session::new(VM::default());
+    assert!(session.eval("(def x (/ 1 0))").is_err());
+    // x was never set, so it is not defined either
+    assert!(session.eval("x").unwrap_err().contains("symbol `x` not found"));
+    assert_eq!(Some(Value::Int(2)), session.eval("(def x 2)").unwrap());
+    assert_eq!(Some(Value::Int(3)), session.eval("(def y (+ x 1))").unwrap());
+    assert_eq!(Some(Value::Int(5)), session.eval("(+ x y)").unwrap());
+
+    // a failed line with several forms forgets all of them
+    assert!(session.eval("(def a 1) (def b (car 1))").is_err());
+    assert!(session.eval("a").is_err());
+    assert_eq!(Some(Value::Int(1)), session.eval("(def a 1)").unwrap());
+    assert_eq!(None, session.eval("; nothing").unwrap());
+
+    // so does a line that fails to compile
+    assert!(session.eval("(def c 1) (nosuch)").unwrap_err().contains("symbol `nosuch` not found"));
+    assert!(session.eval("c").unwrap_err().contains("symbol `c` not found"));
+    assert_eq!(Some(Value::Int(5)), session.eval("(def c 5)").unwrap());
+    assert_eq!(Some(Value::Int(6)), session.eval("(+ a c)").unwrap());
+
+    // and the macros it defined
+    assert!(session.eval("(defmacro twice (x) `(+ ,x ,x)) (twice 1) (nosuch)").is_err());
+    assert!(session.eval("(twice 1)").unwrap_err().contains("symbol `twice` not found"));
+    assert_eq!(Some(Value::Int(4)), session.eval("(defmacro twice (x) `(* 2 ,x)) (twice 2)").unwrap());
+    assert!(session.eval("(defmacro half (x) `(/ ,x 2)) (nosuch)").is_err());
+    assert_eq!(Some(Value::Int(3)), session.eval("(defmacro half (x) `(/ ,x 2)) (half (twice 3))").unwrap());
+}
+
+#[test]
+fn test_call_argc() {
+    let run = |source: &str| {
+        let forms = parser::parse_program(source).unwrap();
+        let program = GenEnv::new().generate_program(&forms).unwrap();
+        VM::default().run(&program)
+    };
+    let err = run("(def f (fn (a) a)) (f 1 2)").unwrap_err();
+    assert_eq!(ErrorKind::WrongNumberOfArgument(1, 2), err.kind);
+    let err = run("(def f (fn (a b) a)) (def g (fn () (f 1))) (g)").unwrap_err();
+    assert_eq!(ErrorKind::WrongNumberOfArgument(2, 1), err.kind);
+    assert!(run("(def f (fn (a) a)) (f 1)").is_ok());
+}
+
+#[test]
+fn test_arithmetic_errors() {
+    let run = |source: &str| {
+        let forms = parser::parse_program(source).unwrap();
+        let program = GenEnv::new().generate_program(&forms).unwrap();
+        VM::default().run(&program).unwrap_err().kind
+    };
+    assert_eq!(ErrorKind::DivisionByZero, run("(/ 1 0.0)"));
+    assert_eq!(ErrorKind::DivisionByZero, run("(% 2.5 0)"));
+    assert!(matches!(run("(- \\a)"), ErrorKind::TypeMismatch(_)));
+    assert!(matches!(run("(def s \"a\") (/ s 0)"), ErrorKind::TypeMismatch(_)));
+}
@@ -1,12 +1,24 @@
 use axolotl::vm::machine::VM;
 use axolotl::vm::bytecode::ByteCode::{self, *};
 use axolotl::vm::value::Value;
+use axolotl::vm::object::ObjType;
+use axolotl::vm::error::{ErrorKind, RuntimeError};
+use std::rc::Rc;
+use std::io::Cursor;
+
+mod common;
+use common::Output;
 
 fn run_prog(program: Vec<ByteCode>) -> VM {
     let mut machine = VM::default();
-    machine.run(&program);
+    machine.run(&program).unwrap();
     machine
 }
+
+fn run_err(program: Vec<ByteCode>) -> RuntimeError {
+    let mut machine = VM::default();
+    machine.run(&program).unwrap_err()
+}
 #[test]
 fn test_add_two() {
     let program = vec![
@@ -84,14 +96,69 @@ fn test_conditional_jmp() {
 }
 
 #[test]
-#[should_panic(expected = "[RUNTIME]: STACK UNDERFLOW")]
 fn stack_underflow() {
     let program = vec![
         Push(Value::Int(1)),
         Pop,
         Pop
     ];
-    run_prog(program);  
+    let err = run_err(program);
+    assert_eq!(ErrorKind::StackUnderflow, err.kind);
+    assert_eq!(2, err.pc);
+    assert_eq!(Some(Pop), err.byte);
+    assert!(err.stack.is_empty());
+}
+
+#[test]
+fn test_runtime_errors() {
+    let err = run_err(vec![
+        Push(Value::Int(1)),
+        Push(Value::Bool(true)),
+        Add,
+        HALT
+    ]);
+    assert!(matches!(err.kind, ErrorKind::TypeMismatch(_)));
+    assert_eq!([Value::Int(1)], err.stack[..]);
+
+    let err = run_err(vec![Push(Value::Int(1)), Push(Value::Int(0)), Div, HALT]);
+    assert_eq!(ErrorKind::DivisionByZero, err.kind);
+    // whatever the types are
+    let err = run_err(vec![Push(Value::Int(1)), Push(Value::Float(0.0)), Div, HALT]);
+    assert_eq!(ErrorKind::DivisionByZero, err.kind);
+    let err = run_err(vec![Push(Value::Float(1.5)), Push(Value::Nil), Rem, HALT]);
+    assert_eq!(ErrorKind::DivisionByZero, err.kind);
+    // and the types are checked first
+    let err = run_err(vec![Push(Value::Bool(true)), Push(Value::Int(0)), Div, HALT]);
+    assert!(matches!(err.kind, ErrorKind::TypeMismatch(_)));
+    let err = run_err(vec![Push(Value::Char('a' as u32)), Push(Value::Int(-1)), Mul, HALT]);
+    assert!(matches!(err.kind, ErrorKind::TypeMismatch(_)));
+
+    let err = run_err(vec![Push(Value::Bool(false)), Ret, HALT]);
+    assert_eq!(ErrorKind::InvalidAddress, err.kind);
+
+    let err = run_err(vec![Push(Value::Int(1)), CallTopFn(0), HALT]);
+    assert_eq!(ErrorKind::NotAFunction, err.kind);
+    // only a closure being called has captures
+    let err = run_err(vec![LoadRec(0), HALT]);
+    assert_eq!(ErrorKind::InvalidAddress, err.kind);
+
+    // the function takes 1, the call site passes 2
+    let id = func(1, vec![Arg(0)]);
+    let err = run_err(vec![Push(Value::Int(1)), Push(Value::Int(2)), Push(id), CallTopFn(2), HALT]);
+    assert_eq!(ErrorKind::WrongNumberOfArgument(1, 2), err.kind);
+    assert_eq!([Value::Int(1), Value::Int(2)], err.stack[..]);
+
+    let err = run_err(vec![Push(Value::Int(1))]);
+    assert_eq!(ErrorKind::PcOutOfBounds, err.kind);
+    assert_eq!(None, err.byte);
+}
+
+#[test]
+fn test_set_past_the_end() {
+    // slot 0 and 1 were given to defs that never ran
+    let machine = run_prog(vec![Push(Value::Int(1)), Set(2), Get(2), Get(0), HALT]);
+    assert_eq!([Value::Nil, Value::Nil, Value::Int(1)], machine.constant_pool[..]);
+    assert_eq!([Value::Int(1), Value::Nil], machine.stack[..]);
 }
 
 #[test]
@@ -109,3 +176,290 @@ fn test_function_call() {
     assert!(machine.stack.is_empty());
 }
 
+
+fn func(argc: usize, body: Vec<ByteCode>) -> Value {
+    Value::Ref(Rc::new(ObjType::Func(argc, body)))
+}
+
+#[test]
+fn test_halt_in_function() {
+    let halt = func(1, vec![Arg(0), Dup, StoreLocal(1), HALT]);
+    let program = vec![Push(Value::Int(7)), Push(halt), CallTopFn(1), HALT];
+    let machine = run_prog(program);
+    assert_eq!([Value::Int(7)], machine.stack[..]);
+    assert!(machine.frames.is_empty());
+    assert!(machine.locals.is_empty());
+}
+
+#[test]
+fn test_recursive_function() {
+    // n <= 1 ? 1 : n * fact(n - 1)
+    let fact = func(1, vec![
+        Arg(0),
+        Push(Value::Int(1)),
+        LessEq,
+        PopJmpIf(11),
+        Pop,
+        Sub,
+        Get(0),
+        CallTopFn(1),
+        Arg(0),  // still n after the recursive call
+        Mul,
+        Jmp(14),
+        Pop,     // base case
+        Pop,
+        Push(Value::Int(1)),
+    ]);
+    let program = vec![
+        Push(fact),
+        Set(0),
+        Push(Value::Int(5)),
+        Get(0),
+        CallTopFn(1),
+        HALT
+    ];
+    let machine = run_prog(program);
+    assert_eq!([Value::Int(120)], machine.stack[..]);
+    assert_eq!(1, machine.constant_pool.len());
+    assert!(machine.frames.is_empty());
+    assert!(machine.locals.is_empty());
+}
+
+#[test]
+fn test_locals() {
+    // (a, b) -> { c = a - b; c * c }
+    let square_diff = func(2, vec![
+        Arg(0),
+        Arg(1),
+        Sub,
+        StoreLocal(2),
+        LoadLocal(2),
+        LoadLocal(2),
+        Mul,
+    ]);
+    let program = vec![
+        Push(Value::Int(7)),
+        Push(Value::Int(4)),
+        Push(square_diff),
+        CallTopFn(2),
+        HALT
+    ];
+    let machine = run_prog(program);
+    assert_eq!([Value::Int(9)], machine.stack[..]);
+    assert!(machine.locals.is_empty());
+}
+
+#[test]
+fn test_deep_recursion() {
+    // n == 0 ? 0 : 1 + f(n - 1), too deep for the rust stack if calls recursed natively
+    let count = func(1, vec![
+        Arg(0),
+        Push(Value::Int(0)),
+        Eq,
+        PopJmpIf(12),
+        Pop,
+        Pop,
+        Dec,
+        Get(0),
+        CallTopFn(1),
+        Push(Value::Int(1)),
+        Add,
+        Jmp(13),
+        Pop,  // leaves the 0
+    ]);
+    let program = vec![
+        Push(count),
+        Set(0),
+        Push(Value::Int(200_000)),
+        Get(0),
+        CallTopFn(1),
+        HALT
+    ];
+    let machine = run_prog(program);
+    assert_eq!([Value::Int(200_000)], machine.stack[..]);
+}
+
+#[test]
+fn test_tail_call() {
+    // n == 0 ? 0 : countdown(n - 1)
+    let countdown = func(1, vec![
+        Arg(0),
+        Push(Value::Int(0)),
+        Eq,
+        PopJmpIf(9),
+        Pop,
+        Pop,
+        Dec,
+        Get(0),
+        TailCallTopFn(1),
+        Pop,
+    ]);
+    let program = vec![
+        Push(countdown),
+        Set(0),
+        Push(Value::Int(1_000_000)),
+        Get(0),
+        CallTopFn(1),
+        HALT
+    ];
+    let machine = run_prog(program);
+    assert_eq!([Value::Int(0)], machine.stack[..]);
+    // the frame and locals areas never grew
+    assert!(machine.frames.capacity() <= 16);
+    assert!(machine.locals.capacity() <= 64);
+}
+
+#[test]
+fn test_native() {
+    let mut machine = VM::default();
+    let index = machine.register_native("sub", 2, |_vm, args| {
+        let mut a = args[0].clone();
+        a.sub(args[1].clone())?;
+        Ok(a)
+    });
+    let program = vec![
+        Push(Value::Int(5)),
+        Push(Value::Int(3)),
+        CallNative(index),
+        HALT
+    ];
+    machine.run(&program).unwrap();
+    assert_eq!([Value::Int(2)], machine.stack[..]);
+
+    machine.reset_pc();
+    machine.register_native("fail", 0, |_vm, _args| Err(ErrorKind::Native("no clock".to_string())));
+    let err = machine.run(&[CallNative(1), HALT]).unwrap_err();
+    assert_eq!(ErrorKind::Native("no clock".to_string()), err.kind);
+}
+
+#[test]
+fn test_io() {
+    let output = Output::default();
+    let mut machine = VM::default()
+        .set_output(output.clone())
+        .set_input(Cursor::new("hello\r\nworld\n"));
+    let program = vec![
+        Push(Value::Int(1)),
+        Print,
+        Push(Value::Char('x' as u32)),
+        PrintLn,
+        ReadLine,
+        PrintLn,
+        ReadLine,
+        Print,
+        ReadLine,  // EOF
+        Push(Value::Int(3)),
+        Exit,
+        Push(Value::Int(4)),
+        HALT
+    ];
+    machine.run(&program).unwrap();
+    assert_eq!("1x\nhello\nworld", output.text());
+    assert_eq!([Value::Nil], machine.stack[..]);
+    assert_eq!(Some(3), machine.exit_code);
+}
+
+#[test]
+fn test_list_ops() {
+    let list = |values: Vec<i64>| {
+        let mut program: Vec<ByteCode> = values.iter().map(|i| Push(Value::Int(*i))).collect();
+        program.push(CollectList(values.len()));
+        program
+    };
+
+    let machine = run_prog([list(vec![1, 2, 3]), vec![Dup, Car, Swap, Cdr, Car, HALT]].concat());
+    assert_eq!([Value::Int(1), Value::Int(2)], machine.stack[..]);
+
+    let machine = run_prog([vec![Push(Value::Int(0))], list(vec![1]), vec![Cons, Cdr, Car, HALT]].concat());
+    assert_eq!([Value::Int(1)], machine.stack[..]);
+
+    // nil is the empty list
+    let machine = run_prog(vec![Push(Value::Int(1)), Push(Value::Nil), Cons, Car, HALT]);
+    assert_eq!([Value::Int(1)], machine.stack[..]);
+
+    let machine = run_prog([list(vec![1]), vec![Cdr, Dup, IsNil, Swap, Car, Push(Value::Nil), IsNil, Push(Value::Int(0)), IsNil, HALT]].concat());
+    assert_eq!([Value::Bool(true), Value::Nil, Value::Bool(true), Value::Bool(false)], machine.stack[..]);
+
+    assert_eq!(ErrorKind::TypeMismatch("[VM]: Not a list"), run_err(vec![Push(Value::Int(1)), Car, HALT]).kind);
+}
+
+fn str(s: &str) -> Value {
+    Value::Ref(Rc::new(ObjType::Str(s.to_string())))
+}
+
+#[test]
+fn test_string_ops() {
+    let machine = run_prog(vec![
+        Push(Value::Char('o' as u32)), Push(Value::Char('k' as u32)), CollectCharList(2),
+        Push(str("axo")), Push(str("lotl")), Concat,
+        Dup, Len,
+        Copy(1), Push(Value::Int(3)), CharAt,
+        Copy(2), Push(Value::Int(3)), Push(Value::Int(7)), Substr,
+        HALT
+    ]);
+    assert_eq!([str("ok"), str("axolotl"), Value::Int(7), Value::Char('l' as u32), str("lotl")], machine.stack[..]);
+
+    let machine = run_prog(vec![Push(str("λx")), StrToList, Dup, Len, Swap, ListToStr, HALT]);
+    assert_eq!([Value::Int(2), str("λx")], machine.stack[..]);
+
+    // Eq compares the content, Seq compares everything
+    let machine = run_prog(vec![
+        Push(str("a")), Push(str("a")), Eq, Swap, Pop, Swap, Pop,
+        Push(str("a")), Push(str("b")), Seq, Swap, Pop, Swap, Pop,
+        Push(str("a")), Push(str("b")), Less, Swap, Pop, Swap, Pop,
+        HALT
+    ]);
+    assert_eq!([Value::Bool(true), Value::Bool(false), Value::Bool(true)], machine.stack[..]);
+
+    assert_eq!(ErrorKind::IndexOutOfRange, run_err(vec![Push(str("ab")), Push(Value::Int(2)), CharAt, HALT]).kind);
+    assert_eq!(ErrorKind::IndexOutOfRange,
+               run_err(vec![Push(str("ab")), Push(Value::Int(1)), Push(Value::Int(3)), Substr, HALT]).kind);
+    assert!(matches!(run_err(vec![Push(str("a")), Push(Value::Int(1)), Concat, HALT]).kind, ErrorKind::TypeMismatch(_)));
+    assert!(matches!(run_err(vec![Push(str("a")), Push(Value::Int(1)), Eq, HALT]).kind, ErrorKind::TypeMismatch(_)));
+}
+
+#[test]
+fn test_string_asm() {
+    let program = axolotl::asm::compile_to_enum(std::fs::read_to_string("asm/string.asm").unwrap()).unwrap();
+    let machine = run_prog(program);
+    assert_eq!([Value::Int(7), Value::Char('l' as u32), str("ok")], machine.stack[..]);
+}
+
+// n < 2 ? n : fib(n - 1) + fib(n - 2), fib is at Get(0)
+fn fib_program(n: i64) -> Vec<ByteCode> {
+    let fib = vec![
+        Arg(0),
+        Push(Value::Int(2)),
+        Less,
+        PopJmpIf(16),
+        Pop,
+        Pop,
+        Dec,
+        Get(0),
+        CallTopFn(1),
+        Arg(0),
+        Push(Value::Int(2)),
+        Sub,
+        Get(0),
+        CallTopFn(1),
+        Add,
+        Jmp(17),
+        Pop,  // base case
+    ];
+    vec![
+        Push(Value::Ref(Rc::new(ObjType::Func(1, fib)))),
+        Set(0),
+        Push(Value::Int(n)),
+        Get(0),
+        CallTopFn(1),
+        HALT
+    ]
+}
+
+// a quarter million calls, each one returns to the loop instead of a native frame
+#[test]
+fn test_recursive_fib() {
+    let machine = run_prog(fib_program(25));
+    assert_eq!([Value::Int(75025)], machine.stack[..]);
+    assert!(machine.frames.is_empty());
+}